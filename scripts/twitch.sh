@@ -25,7 +25,7 @@ USE_API=1
 CHAT_FORMAT=vicuna
 MAX_TOKENS=800
 TEMPERATURE=0.8
-CONTEXT_SIZE=8000
+HISTORY_TOKENS=2000
 QUANTIZED=0
 KEEP_HISTORY=1
 SD_MAX_LENGTH=50
@@ -132,7 +132,7 @@ DYLD_LIBRARY_PATH=`pwd`:/usr/local/lib:$DYLD_LIBRARY_PATH \
     $USE_CANDLE_CMD \
     --sd-text-min $SD_TEXT_MIN \
     --sd-max-length $SD_MAX_LENGTH \
-    --llm-history-size $CONTEXT_SIZE \
+    --llm-history-tokens $HISTORY_TOKENS \
     --chat-format $CHAT_FORMAT \
     --model-id $MODEL_ID \
     --temperature $TEMPERATURE \
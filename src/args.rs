@@ -27,12 +27,12 @@ pub struct Args {
     )]
     pub query: String,
 
-    /// Chat Format - LLM chat format to use, llama2, chatml, gemma, ""
+    /// Chat Format - LLM chat format preset, template file or model, "" uses the model default
     #[clap(
         long,
         env = "CHAT_FORMAT",
         default_value = "",
-        help = "Chat Format - LLM chat format to use, llama2, chatml, gemma, \"\""
+        help = "Chat Format - LLM chat format to use, llama2, llama3, phi3, chatml, google, vicuna, a .toml turn template, a .jinja template, a tokenizer_config.json with a chat_template, \"model\" for the tokenizer_config.json in --model-dir, \"\" uses the candle model's own format, or no template with --api-completions."
     )]
     pub chat_format: String,
 
@@ -59,7 +59,7 @@ pub struct Args {
         long,
         env = "QUANTIZED",
         default_value = "false",
-        help = "Quantized, it will use a quantized LLM to generate output faster on CPUs or GPUs."
+        help = "Quantized, it will use a quantized LLM to generate output faster on CPUs or GPUs. Gemma loads a llama.cpp or candle tensor-tools GGUF given with --model-weights, a model-q4k.gguf from --model-dir, or gemma-2b-it q8_0 from the hub."
     )]
     pub quantized: bool,
 
@@ -72,6 +72,60 @@ pub struct Args {
     )]
     pub top_p: f32,
 
+    /// Top K
+    #[clap(
+        long,
+        env = "TOP_K",
+        default_value_t = 0,
+        help = "Top K sampling for local models, only the K most likely tokens are kept, 0 disables it."
+    )]
+    pub top_k: usize,
+
+    /// Min P
+    #[clap(
+        long,
+        env = "MIN_P",
+        default_value = "0.0",
+        help = "Min P sampling for local models, drops tokens less likely than min-p times the most likely token, 0.0 disables it."
+    )]
+    pub min_p: f32,
+
+    /// Repeat Penalty
+    #[clap(
+        long,
+        env = "REPEAT_PENALTY",
+        default_value = "1.1",
+        help = "Repeat penalty for local models, 1.0 disables it."
+    )]
+    pub repeat_penalty: f32,
+
+    /// Repeat Last N
+    #[clap(
+        long,
+        env = "REPEAT_LAST_N",
+        default_value_t = 64,
+        help = "Number of recent tokens the repeat penalty applies to for local models."
+    )]
+    pub repeat_last_n: usize,
+
+    /// Seed
+    #[clap(
+        long,
+        env = "SEED",
+        default_value_t = 0,
+        help = "Sampling seed for local models so runs are reproducible, 0 uses a random seed per request."
+    )]
+    pub seed: u64,
+
+    /// Stop - comma separated stop sequences
+    #[clap(
+        long,
+        env = "STOP",
+        default_value = "",
+        help = "Stop sequences, comma separated, generation ends before any of them, \\n is a newline. Local models also stop at their chat format turn markers."
+    )]
+    pub stop: String,
+
     /// Presence Penalty
     #[clap(
         long,
@@ -126,14 +180,203 @@ pub struct Args {
     )]
     pub llm_path: String,
 
-    /// LLM History size
+    /// API Completions - send a formatted prompt instead of chat messages
+    #[clap(
+        long,
+        env = "API_COMPLETIONS",
+        default_value_t = false,
+        help = "Send a prompt formatted with --chat-format to a /v1/completions style endpoint instead of chat messages, for servers and base models without a chat template. The default --llm-path becomes /v1/completions."
+    )]
+    pub api_completions: bool,
+
+    /// LLM Image - image shown to a vision LLM with each prompt
+    #[clap(
+        long,
+        env = "LLM_IMAGE",
+        default_value = "",
+        help = "Image sent with each prompt to a vision capable LLM with the OpenAI API and --llm-vision, \"sd\" for the latest Stable Diffusion frame or a path to an image file such as a stream thumbnail, re-read each turn."
+    )]
+    pub llm_image: String,
+
+    /// LLM Vision - the API model accepts images
+    #[clap(
+        long,
+        env = "LLM_VISION",
+        default_value_t = false,
+        help = "The model behind the OpenAI API accepts images in chat messages, required for --llm-image since text only servers reject or ignore them."
+    )]
+    pub llm_vision: bool,
+
+    /// Server Address - embedded OpenAI compatible server
+    #[clap(
+        long,
+        env = "SERVER_ADDR",
+        default_value = "",
+        help = "Address like 0.0.0.0:8080 to serve /v1/chat/completions, /v1/audio/speech and /v1/images/generations from the bot's local candle models, never the OpenAI API backend, empty to not run the server."
+    )]
+    pub server_addr: String,
+
+    /// Server API Key - bearer key required by the embedded server
+    #[clap(
+        long,
+        env = "SERVER_API_KEY",
+        default_value = "",
+        help = "API key clients of the embedded server must send as a Bearer token, empty to allow any client."
+    )]
+    pub server_api_key: String,
+
+    /// Server Queue Size - chat requests the embedded server runs at once
+    #[clap(
+        long,
+        env = "SERVER_QUEUE_SIZE",
+        default_value_t = 2,
+        help = "Max chat, speech and image requests each of the embedded server running or queued at once, more get a 429. Keep it below --model-queue-size so the bot's own turns always fit in the model queue."
+    )]
+    pub server_queue_size: usize,
+
+    /// Control Address - web control panel and REST API
+    #[clap(
+        long,
+        env = "CONTROL_ADDR",
+        default_value = "",
+        help = "Address like 127.0.0.1:8090 for the web control panel and REST API to inject queries, change the system prompt, pause, switch voices and models and shut down, empty to disable. It has no authentication, bind it to localhost or a trusted network."
+    )]
+    pub control_addr: String,
+
+    /// LLM History tokens - history limit in tokens, replaces the byte based --llm-history-size
+    #[clap(
+        long,
+        env = "LLM_HISTORY_TOKENS",
+        default_value = "4096",
+        help = "LLM History size in tokens, oldest whole messages are dropped past it (0 is only limited by the context size). Replaces --llm-history-size / LLM_HISTORY_SIZE which counted bytes, roughly 4 bytes per token."
+    )]
+    pub llm_history_tokens: usize,
+
+    /// LLM History size - deprecated byte based history limit, see --llm-history-tokens
     #[clap(
         long,
         env = "LLM_HISTORY_SIZE",
-        default_value = "16768",
-        help = "LLM History size (0 is unlimited)."
+        hide = true,
+        help = "Deprecated, use --llm-history-tokens. History size in bytes, converted to tokens at roughly 4 bytes per token."
+    )]
+    pub llm_history_size: Option<usize>,
+
+    /// Context size - LLM context window in tokens
+    #[clap(
+        long,
+        env = "CONTEXT_SIZE",
+        default_value_t = 0,
+        help = "LLM context window in tokens, the prompt is trimmed to fit it with room for max tokens, 0 uses the local model's window or no limit for the API."
+    )]
+    pub context_size: usize,
+
+    /// LLM History Summarize - fold older turns into a memory instead of dropping them
+    #[clap(
+        long,
+        env = "LLM_HISTORY_SUMMARIZE",
+        default_value_t = false,
+        help = "Summarize older turns with the LLM into a rolling memory system message when the history is full, instead of dropping them."
+    )]
+    pub llm_history_summarize: bool,
+
+    /// LLM History Keep - recent messages kept verbatim when summarizing
+    #[clap(
+        long,
+        env = "LLM_HISTORY_KEEP",
+        default_value_t = 6,
+        help = "Number of most recent messages kept verbatim when the history is summarized."
+    )]
+    pub llm_history_keep: usize,
+
+    /// LLM Summary Prompt - instructions for summarizing the history
+    #[clap(
+        long,
+        env = "LLM_SUMMARY_PROMPT",
+        default_value = "You keep the memory of a long running show. Merge the previous memory and the conversation into one concise summary that keeps the story, characters, open threads and facts needed to continue it. Answer with the summary only.",
+        help = "Instructions for summarizing older history into the memory."
+    )]
+    pub llm_summary_prompt: String,
+
+    /// LLM Summary Max Tokens
+    #[clap(
+        long,
+        env = "LLM_SUMMARY_MAX_TOKENS",
+        default_value_t = 400,
+        help = "Max tokens for the history memory summary."
+    )]
+    pub llm_summary_max_tokens: usize,
+
+    /// Session - name of the session to save and restore the conversation with
+    #[clap(
+        long,
+        env = "SESSION",
+        default_value = "",
+        help = "Session name, the conversation history and counters are saved each turn and restored on startup."
+    )]
+    pub session: String,
+
+    /// Session Dir - directory for the session files
+    #[clap(
+        long,
+        env = "SESSION_DIR",
+        default_value = "sessions",
+        help = "Directory the session files are saved in."
+    )]
+    pub session_dir: String,
+
+    /// Session Export - transcript file written each turn, .md for Markdown or .json
+    #[clap(
+        long,
+        env = "SESSION_EXPORT",
+        default_value = "",
+        help = "Transcript of the session written each turn, Markdown for a .md file, JSON otherwise."
+    )]
+    pub session_export: String,
+
+    /// Tools - local tools the LLM can call through the API
+    #[clap(
+        long,
+        env = "TOOLS",
+        default_value = "",
+        help = "Local tools offered to the LLM with the OpenAI API, comma separated get_system_stats, get_pid_map, get_twitch_chat_history or all."
+    )]
+    pub tools: String,
+
+    /// Max Tool Rounds - tool call rounds per answer
+    #[clap(
+        long,
+        env = "MAX_TOOL_ROUNDS",
+        default_value_t = 4,
+        help = "Max rounds of tool calls per answer, the LLM has to answer after that."
+    )]
+    pub max_tool_rounds: usize,
+
+    /// LLM Connect Timeout - seconds to connect to the LLM API
+    #[clap(
+        long,
+        env = "LLM_CONNECT_TIMEOUT",
+        default_value_t = 10,
+        help = "Seconds to wait connecting to the LLM API."
     )]
-    pub llm_history_size: usize,
+    pub llm_connect_timeout: u64,
+
+    /// LLM Read Timeout - seconds to wait for a response or the next streamed chunk
+    #[clap(
+        long,
+        env = "LLM_READ_TIMEOUT",
+        default_value_t = 120,
+        help = "Seconds to wait for the LLM API to respond or send the next streamed chunk."
+    )]
+    pub llm_read_timeout: u64,
+
+    /// LLM Max Retries - retries of a failed LLM API request
+    #[clap(
+        long,
+        env = "LLM_MAX_RETRIES",
+        default_value_t = 3,
+        help = "Retries with exponential backoff for network errors, timeouts, rate limits and server errors, honoring Retry-After."
+    )]
+    pub llm_max_retries: u32,
 
     /// Clear History - clear the history of the LLM each iteration
     #[clap(
@@ -180,6 +423,15 @@ pub struct Args {
     )]
     pub metavoice_tts: bool,
 
+    /// MetaVoice model dir - local directory with the metavoice and encodec files
+    #[clap(
+        long,
+        env = "METAVOICE_MODEL_DIR",
+        default_value = "",
+        help = "Local MetaVoice directory with first_stage.meta.json, first_stage_q4k.gguf, second_stage.safetensors, spk_emb.safetensors and encodec_24khz.safetensors, loads offline without the hub."
+    )]
+    pub metavoice_model_dir: String,
+
     /// OAI_TTS as text to speech from openai
     #[clap(
         long,
@@ -261,6 +513,24 @@ pub struct Args {
     )]
     pub ai_os_stats: bool,
 
+    /// Companion service processes to report in the system stats
+    #[clap(
+        long,
+        env = "AI_OS_STATS_PROCESSES",
+        default_value = "mimic3-server,webui.sh,launch.py,llama-server",
+        help = "Comma separated process or script names of companion services (mimic3, SD webui, llama server) to report per-process CPU/RSS for in the system stats, the bot itself is always included."
+    )]
+    pub ai_os_stats_processes: String,
+
+    /// System stats history size in samples for trend analysis
+    #[clap(
+        long,
+        env = "AI_OS_STATS_HISTORY",
+        default_value_t = 60,
+        help = "System stats history size in samples, used to compute min/max/average and forecasts of the stats trends, 0 is off."
+    )]
+    pub ai_os_stats_history: usize,
+
     /// run as a daemon monitoring the specified stats
     #[clap(
         long,
@@ -445,6 +715,33 @@ pub struct Args {
     )]
     pub pcap_channel_size: usize,
 
+    /// Capture Buffer Seconds - seconds of raw packets to keep in memory for error evidence, 0 is off
+    #[clap(
+        long,
+        env = "CAPTURE_BUFFER_SECONDS",
+        default_value_t = 0,
+        help = "Capture Buffer Seconds - seconds of raw packets to keep in memory and dump to a file when a CC or TR 101 290 error is detected, 0 is off."
+    )]
+    pub capture_buffer_seconds: u64,
+
+    /// Capture Buffer Format - ts or pcap
+    #[clap(
+        long,
+        env = "CAPTURE_BUFFER_FORMAT",
+        default_value = "ts",
+        help = "Capture Buffer Format - ts writes the MpegTS payload, pcap writes the full packets."
+    )]
+    pub capture_buffer_format: String,
+
+    /// Capture Buffer Directory - where capture buffer dumps are written
+    #[clap(
+        long,
+        env = "CAPTURE_BUFFER_DIR",
+        default_value = "captures",
+        help = "Capture Buffer Directory - where capture buffer dumps are written."
+    )]
+    pub capture_buffer_dir: String,
+
     /// DEBUG LLM Message History
     #[clap(
         long,
@@ -576,10 +873,55 @@ pub struct Args {
         long,
         env = "CANDLE_LLM",
         default_value = "mistral",
-        help = "which llm to use from candle."
+        help = "which llm to use from candle, mistral, gemma, llama, phi or qwen."
     )]
     pub candle_llm: String,
 
+    /// model queue size - max generation requests waiting on a loaded candle model
+    #[clap(
+        long,
+        env = "MODEL_QUEUE_SIZE",
+        default_value_t = 8,
+        help = "Max generation requests queued for a loaded candle model, more are rejected."
+    )]
+    pub model_queue_size: usize,
+
+    /// model dir - local directory with the candle model files, no hub downloads when set
+    #[clap(
+        long,
+        env = "MODEL_DIR",
+        default_value = "",
+        help = "Local directory with tokenizer.json, config.json and weights for the candle LLM, loads offline without the hub."
+    )]
+    pub model_dir: String,
+
+    /// model tokenizer - local tokenizer.json for the candle LLM
+    #[clap(
+        long,
+        env = "MODEL_TOKENIZER",
+        default_value = "",
+        help = "Local tokenizer.json file for the candle LLM, overrides the one in --model-dir."
+    )]
+    pub model_tokenizer: String,
+
+    /// model config - local config.json for the candle LLM
+    #[clap(
+        long,
+        env = "MODEL_CONFIG",
+        default_value = "",
+        help = "Local config.json file for the candle LLM, overrides the one in --model-dir."
+    )]
+    pub model_config: String,
+
+    /// model weights - local weight files for the candle LLM, comma separated
+    #[clap(
+        long,
+        env = "MODEL_WEIGHTS",
+        default_value = "",
+        help = "Local safetensors or gguf weight files for the candle LLM, comma separated, overrides --model-dir."
+    )]
+    pub model_weights: String,
+
     /// sd height
     #[clap(long, env = "SD_HEIGHT", default_value_t = 512, help = "SD Height.")]
     pub sd_height: usize,
@@ -633,6 +975,15 @@ pub struct Args {
     )]
     pub sd_model: String,
 
+    /// Stable Diffusion model dir - local directory laid out like the hub repos
+    #[clap(
+        long,
+        env = "SD_MODEL_DIR",
+        default_value = "",
+        help = "Local Stable Diffusion directory with unet/, vae/, text_encoder/ (text_encoder_2/ for xl/turbo) weights and tokenizer/tokenizer.json (tokenizer_2/tokenizer.json for xl/turbo), loads offline without the hub."
+    )]
+    pub sd_model_dir: String,
+
     /// sd_n_steps - number of steps for SD
     #[clap(long, env = "SD_N_STEPS", help = "SD N Steps.")]
     pub sd_n_steps: Option<usize>,
@@ -763,6 +1114,15 @@ pub struct Args {
     )]
     pub twitch_model: String,
 
+    /// Twitch model dir - local directory with the twitch model files
+    #[clap(
+        long,
+        env = "TWITCH_MODEL_DIR",
+        default_value = "",
+        help = "Local directory with the twitch LLM model files, loads offline without the hub."
+    )]
+    pub twitch_model_dir: String,
+
     /// Twitch Max Tokens - max tokens for LLM
     #[clap(
         long,
@@ -772,6 +1132,15 @@ pub struct Args {
     )]
     pub twitch_max_tokens_chat: usize,
 
+    /// Twitch Temperature - sampling temperature for chat replies
+    #[clap(
+        long,
+        env = "TWITCH_TEMPERATURE",
+        default_value = "0.8",
+        help = "Twitch Temperature for chat replies, the other sampling options are shared with the main LLM."
+    )]
+    pub twitch_temperature: f32,
+
     //// Twitch Max Tokens LLM - max tokens for LLM
     #[clap(
         long,
@@ -798,4 +1167,45 @@ pub struct Args {
         help = "NDI Timeout."
     )]
     pub ndi_timeout: u64,
-}
\ No newline at end of file
+
+    /// Config - TOML or YAML configuration file with profiles
+    #[clap(
+        long,
+        env = "CONFIG",
+        default_value = "",
+        help = "TOML or YAML config file of option names and values under [defaults] and [profiles.<name>], command line flags and environment variables override it. Prompts, voices, the poll interval and Stable Diffusion settings reload when the file changes."
+    )]
+    pub config: String,
+
+    /// Profile - profile of the config file to run
+    #[clap(
+        long,
+        env = "PROFILE",
+        default_value = "",
+        help = "Profile in the config file to run, like twitch-storyteller, mpeg-analyzer or system-health, empty for only the defaults."
+    )]
+    pub profile: String,
+
+    /// Schedule - TOML or YAML rundown of show segments
+    #[clap(
+        long,
+        env = "SCHEDULE",
+        default_value = "",
+        help = "TOML or YAML schedule of [[segments]] that switch the query, system prompt, greeting, mimic3 voice and Stable Diffusion model at a time of day (at = \"21:00\"), every hour (at = \"*:00\") or after the previous segment told its stories, without restarting."
+    )]
+    pub schedule: String,
+}
+
+impl Args {
+    /// Convert deprecated settings to their replacements, warning that they are still set.
+    pub fn convert_deprecated(&mut self) {
+        if let Some(bytes) = self.llm_history_size.take() {
+            self.llm_history_tokens = bytes / 4;
+            log::warn!(
+                "--llm-history-size / LLM_HISTORY_SIZE is deprecated, {} bytes converted to --llm-history-tokens {}.",
+                bytes,
+                self.llm_history_tokens
+            );
+        }
+    }
+}
@@ -0,0 +1,407 @@
+/*
+ * candle_llm.rs
+ * -------------
+ * Llama 3 (safetensors or quantized GGUF), Phi 3 and Qwen 2 local models
+ * through candle, streaming tokens over a Sender like the Mistral and Gemma models.
+*/
+
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use crate::llm_backend::GenerationParams;
+use crate::model_files::{require_files, ModelFiles};
+use crate::sampling::Sampler;
+use crate::stop_sequences::{StopCheck, StopMatcher};
+use anyhow::{Error as E, Result};
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Tensor};
+use candle_examples::token_output_stream::TokenOutputStream;
+use candle_nn::VarBuilder;
+use candle_transformers::models::llama::{Cache as LlamaCache, Config as LlamaConfig, Llama};
+use candle_transformers::models::phi3::{Config as Phi3Config, Model as Phi3};
+use candle_transformers::models::quantized_llama::ModelWeights as QLlama;
+use candle_transformers::models::qwen2::{Config as Qwen2Config, ModelForCausalLM as Qwen2};
+use hf_hub::{api::sync::Api, Repo, RepoType};
+use log::{debug, info};
+use std::path::PathBuf;
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc::Sender;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LlmFamily {
+    Llama,
+    Phi,
+    Qwen,
+}
+
+impl LlmFamily {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "llama" | "llama3" => Some(LlmFamily::Llama),
+            "phi" | "phi3" => Some(LlmFamily::Phi),
+            "qwen" | "qwen2" => Some(LlmFamily::Qwen),
+            _ => None,
+        }
+    }
+
+    fn default_repo(&self, quantized: bool) -> &'static str {
+        match self {
+            LlmFamily::Llama => {
+                if quantized {
+                    "QuantFactory/Meta-Llama-3-8B-Instruct-GGUF"
+                } else {
+                    "meta-llama/Meta-Llama-3-8B-Instruct"
+                }
+            }
+            LlmFamily::Phi => "microsoft/Phi-3-mini-4k-instruct",
+            LlmFamily::Qwen => "Qwen/Qwen2-1.5B-Instruct",
+        }
+    }
+
+    // End of turn tokens, whichever the tokenizer knows stop generation
+    fn eos_tokens(&self) -> &'static [&'static str] {
+        match self {
+            LlmFamily::Llama => &["<|eot_id|>", "<|end_of_text|>", "</s>"],
+            LlmFamily::Phi => &["<|end|>", "<|endoftext|>"],
+            LlmFamily::Qwen => &["<|im_end|>", "<|endoftext|>"],
+        }
+    }
+}
+
+enum Model {
+    Llama(Llama, LlamaCache, LlamaConfig, DType),
+    QuantizedLlama(QLlama),
+    Phi(Phi3),
+    Qwen(Qwen2),
+}
+
+impl Model {
+    // Logits for the last position as a 1d tensor
+    fn forward(&mut self, input: &Tensor, start_pos: usize) -> Result<Tensor> {
+        let logits = match self {
+            Model::Llama(m, cache, _, _) => m.forward(input, start_pos, cache)?.squeeze(0)?,
+            Model::QuantizedLlama(m) => m.forward(input, start_pos)?.squeeze(0)?,
+            Model::Phi(m) => m.forward(input, start_pos)?.squeeze(0)?.squeeze(0)?,
+            Model::Qwen(m) => m.forward(input, start_pos)?.squeeze(0)?.squeeze(0)?,
+        };
+        Ok(logits.to_dtype(DType::F32)?)
+    }
+
+    fn clear_kv_cache(&mut self, device: &Device) -> Result<()> {
+        match self {
+            Model::Llama(_, cache, config, dtype) => {
+                *cache = LlamaCache::new(true, *dtype, config, device)?;
+            }
+            // the quantized llama cache restarts on a forward at position 0
+            Model::QuantizedLlama(_) => {}
+            Model::Phi(m) => m.clear_kv_cache(),
+            Model::Qwen(m) => m.clear_kv_cache(),
+        }
+        Ok(())
+    }
+}
+
+struct TextGeneration {
+    model: Model,
+    device: Device,
+    tokenizer: TokenOutputStream,
+    eos_tokens: Vec<u32>,
+    sampler: Option<Sampler>,
+    stop_sequences: Vec<String>,
+    internal_token_sender: Option<Sender<String>>,
+}
+
+impl TextGeneration {
+    fn new(model: Model, tokenizer: Tokenizer, eos_tokens: Vec<u32>, device: &Device) -> Self {
+        Self {
+            model,
+            tokenizer: TokenOutputStream::new(tokenizer),
+            eos_tokens,
+            sampler: None,
+            stop_sequences: Vec::new(),
+            device: device.clone(),
+            internal_token_sender: None,
+        }
+    }
+
+    /// Set up sampling and the token channel for the next request, the model stays loaded.
+    fn configure(&mut self, params: &GenerationParams, internal_token_sender: Sender<String>) {
+        self.sampler = Some(Sampler::new(params));
+        self.stop_sequences = params.stop.clone();
+        self.internal_token_sender = Some(internal_token_sender);
+    }
+
+    async fn run(&mut self, prompt: &str, sample_len: usize) -> Result<()> {
+        // taken for this run only, dropping it closes the channel when generation ends
+        let internal_token_sender = match self.internal_token_sender.take() {
+            Some(sender) => sender,
+            None => anyhow::bail!("no token channel configured for generation"),
+        };
+        let mut sampler = match self.sampler.take() {
+            Some(sampler) => sampler,
+            None => anyhow::bail!("no sampler configured for generation"),
+        };
+        let mut stop_matcher = StopMatcher::new(&self.stop_sequences);
+        let mut stopped = false;
+        self.model.clear_kv_cache(&self.device)?;
+        self.tokenizer.clear();
+        let mut tokens = self
+            .tokenizer
+            .tokenizer()
+            .encode(prompt, true)
+            .map_err(E::msg)?
+            .get_ids()
+            .to_vec();
+
+        debug!("prompt: {:?}", prompt);
+
+        for index in 0..sample_len {
+            let context_size = if index > 0 { 1 } else { tokens.len() };
+            let start_pos = tokens.len().saturating_sub(context_size);
+            let ctxt = &tokens[start_pos..];
+            let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
+            let logits = self.model.forward(&input, start_pos)?;
+
+            let next_token = sampler.sample(&logits, &tokens)?;
+            tokens.push(next_token);
+            if self.eos_tokens.contains(&next_token) {
+                break;
+            }
+            if let Some(t) = self.tokenizer.next_token(next_token)? {
+                let (text, stop) = match stop_matcher.push(&t) {
+                    StopCheck::Continue(text) => (text, false),
+                    StopCheck::Stop(text) => (text, true),
+                };
+                if !text.is_empty() && internal_token_sender.send(text).await.is_err() {
+                    debug!("Token receiver closed, stopping generation");
+                    break;
+                }
+                if stop {
+                    debug!("Stop sequence reached, stopping generation");
+                    stopped = true;
+                    break;
+                }
+            }
+        }
+
+        // text held back as a possible stop sequence start
+        if !stopped {
+            let rest = match self.tokenizer.decode_rest().map_err(E::msg)? {
+                Some(decoded) => match stop_matcher.push(&decoded) {
+                    StopCheck::Continue(text) => text + &stop_matcher.flush(),
+                    StopCheck::Stop(text) => text,
+                },
+                None => stop_matcher.flush(),
+            };
+            if !rest.is_empty() {
+                let _ = internal_token_sender.send(rest).await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The GGUF file of a hub repo, the Q4_K_M quantization when there are several
+fn hub_gguf_name(repo: &hf_hub::api::sync::ApiRepo, model_id: &str) -> Result<String> {
+    let ggufs: Vec<String> = repo
+        .info()?
+        .siblings
+        .into_iter()
+        .map(|sibling| sibling.rfilename)
+        .filter(|name| name.ends_with(".gguf"))
+        .collect();
+    let preferred = ggufs
+        .iter()
+        .find(|name| name.to_lowercase().contains("q4_k_m"))
+        .or_else(|| ggufs.iter().find(|name| name.as_str() == "model-q4k.gguf"));
+    match (preferred, ggufs.as_slice()) {
+        (Some(name), _) => Ok(name.clone()),
+        (None, [name]) => Ok(name.clone()),
+        (None, []) => anyhow::bail!("no GGUF file in {}", model_id),
+        (None, _) => anyhow::bail!(
+            "{} has several GGUF files and no Q4_K_M one, download one of them for --model-weights: {}",
+            model_id,
+            ggufs.join(", ")
+        ),
+    }
+}
+
+// Safetensors from a hub repo, sharded with an index or a single file
+fn hub_safetensors(repo: &hf_hub::api::sync::ApiRepo) -> Result<Vec<PathBuf>> {
+    match repo.get("model.safetensors.index.json") {
+        Ok(_) => candle_examples::hub_load_safetensors(repo, "model.safetensors.index.json"),
+        Err(_) => Ok(vec![repo.get("model.safetensors")?]),
+    }
+}
+
+/// Llama 3, Phi 3 or Qwen 2 model and tokenizer kept resident between generation requests.
+pub struct CandleLlmModel {
+    family: LlmFamily,
+    pipeline: TextGeneration,
+}
+
+impl CandleLlmModel {
+    pub fn load(
+        family: LlmFamily,
+        quantized: bool,
+        model_id: Option<String>,
+        model_files: &ModelFiles,
+    ) -> Result<Self> {
+        let cpu = false;
+        let use_flash_attn = false;
+        let revision: String = "main".to_string();
+
+        if quantized && family != LlmFamily::Llama {
+            anyhow::bail!(
+                "quantized GGUF weights are only supported for llama, not {:?}",
+                family
+            );
+        }
+        // GGUF files carry their own config
+        let needs_config = !quantized;
+
+        let start = std::time::Instant::now();
+        let (tokenizer_filename, config_filename, filenames) = if model_files.is_local() {
+            // offline, load only from the given local paths
+            let gguf_name = if quantized {
+                Some("model-q4k.gguf")
+            } else {
+                None
+            };
+            let tokenizer_filename = model_files.tokenizer();
+            let config_filename = model_files.config();
+            let filenames = model_files.weights(gguf_name)?;
+            let mut required = vec![tokenizer_filename.clone()];
+            if needs_config {
+                required.push(config_filename.clone());
+            }
+            required.extend(filenames.iter().cloned());
+            require_files(&format!("{:?}", family).to_lowercase(), &required)?;
+            (tokenizer_filename, config_filename, filenames)
+        } else {
+            let api = Api::new()?;
+            let model_id = match &model_id {
+                Some(model_id) if !model_id.is_empty() && model_id != "auto" => model_id.clone(),
+                _ => family.default_repo(quantized).to_string(),
+            };
+            let repo = api.repo(Repo::with_revision(
+                model_id.clone(),
+                RepoType::Model,
+                revision.clone(),
+            ));
+            if quantized {
+                let gguf_name = hub_gguf_name(&repo, &model_id)?;
+                let tokenizer = if model_id == family.default_repo(true) {
+                    // the default GGUF repo has no tokenizer, it comes from the original model
+                    let tokenizer_repo = api.repo(Repo::with_revision(
+                        family.default_repo(false).to_string(),
+                        RepoType::Model,
+                        revision,
+                    ));
+                    tokenizer_repo.get("tokenizer.json")?
+                } else {
+                    match repo.get("tokenizer.json") {
+                        Ok(file) => file,
+                        Err(e) => anyhow::bail!(
+                            "no tokenizer.json in {} ({}), GGUF repos often leave it out, use --model-tokenizer and --model-weights with the original model's tokenizer",
+                            model_id,
+                            e
+                        ),
+                    }
+                };
+                (tokenizer, PathBuf::new(), vec![repo.get(&gguf_name)?])
+            } else {
+                (
+                    repo.get("tokenizer.json")?,
+                    repo.get("config.json")?,
+                    hub_safetensors(&repo)?,
+                )
+            }
+        };
+        info!("retrieved the files in {:?}", start.elapsed());
+        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+        let eos_tokens: Vec<u32> = family
+            .eos_tokens()
+            .iter()
+            .filter_map(|token| tokenizer.token_to_id(token))
+            .collect();
+        if eos_tokens.is_empty() {
+            anyhow::bail!(
+                "cannot find an end of turn token in the {:?} tokenizer",
+                family
+            );
+        }
+
+        let start = std::time::Instant::now();
+        let device = candle_examples::device(cpu)?;
+        let dtype = if device.is_cuda() {
+            DType::BF16
+        } else {
+            DType::F32
+        };
+        let model = if quantized {
+            let filename = &filenames[0];
+            let mut file = std::fs::File::open(filename)?;
+            let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(filename))?;
+            Model::QuantizedLlama(QLlama::from_gguf(content, &mut file, &device)?)
+        } else {
+            let config_file = std::fs::File::open(&config_filename)?;
+            let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
+            match family {
+                LlmFamily::Llama => {
+                    let config: candle_transformers::models::llama::LlamaConfig =
+                        serde_json::from_reader(config_file)?;
+                    let config = config.into_config(use_flash_attn);
+                    let cache = LlamaCache::new(true, dtype, &config, &device)?;
+                    Model::Llama(Llama::load(vb, &config)?, cache, config, dtype)
+                }
+                LlmFamily::Phi => {
+                    let config: Phi3Config = serde_json::from_reader(config_file)?;
+                    Model::Phi(Phi3::new(&config, vb)?)
+                }
+                LlmFamily::Qwen => {
+                    let config: Qwen2Config = serde_json::from_reader(config_file)?;
+                    Model::Qwen(Qwen2::new(&config, vb)?)
+                }
+            }
+        };
+
+        info!("loaded the {:?} model in {:?}", family, start.elapsed());
+
+        Ok(CandleLlmModel {
+            family,
+            pipeline: TextGeneration::new(model, tokenizer, eos_tokens, &device),
+        })
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        self.pipeline.tokenizer.tokenizer()
+    }
+
+    /// Generate a response to the prompt, streaming tokens over the external sender.
+    pub async fn generate(
+        &mut self,
+        prompt: &str,
+        params: &GenerationParams,
+        external_sender: Sender<String>,
+    ) -> Result<()> {
+        info!(
+            "{:?} temp: {:.2} top-p: {:.2} top-k: {} min-p: {:.2} repeat-penalty: {:.2} repeat-last-n: {} seed: {}",
+            self.family,
+            params.temperature,
+            params.top_p,
+            params.top_k,
+            params.min_p,
+            params.repeat_penalty,
+            params.repeat_last_n,
+            params.seed
+        );
+
+        self.pipeline.configure(params, external_sender);
+        self.pipeline.run(prompt, params.max_tokens).await
+    }
+}
@@ -22,6 +22,8 @@ use candle_transformers::models::metavoice::{adapters, gpt, tokenizers, transfor
 #[cfg(feature = "metavoice")]
 use candle_transformers::models::quantized_metavoice::transformer as qtransformer;
 
+#[cfg(feature = "metavoice")]
+use crate::model_files::require_files;
 #[cfg(feature = "metavoice")]
 use candle_core::{DType, IndexOp, Tensor};
 #[cfg(feature = "metavoice")]
@@ -42,7 +44,7 @@ enum Transformer {
 }
 
 #[cfg(feature = "metavoice")]
-pub async fn metavoice(prompt: String) -> Result<Bytes, Error> {
+pub async fn metavoice(prompt: String, model_dir: String) -> Result<Bytes, Error> {
     use tracing_chrome::ChromeLayerBuilder;
     use tracing_subscriber::prelude::*;
 
@@ -54,14 +56,46 @@ pub async fn metavoice(prompt: String) -> Result<Bytes, Error> {
     // Override seed for now
     let mut seed: Option<u64> = Some(299792458);
     let max_tokens = 2000;
-    let first_stage_meta: Option<String> = None;
-    let first_stage_weights: Option<String> = None;
-    let second_stage_weights: Option<String> = None;
-    let encodec_weights: Option<String> = None;
-    let spk_emb: Option<String> = None;
     let dtype = DType::F32;
     let quantized = true;
 
+    // local files when a model directory is given, nothing is fetched from the hub then
+    let local_file = |name: &str| -> Option<String> {
+        if model_dir.is_empty() {
+            None
+        } else {
+            Some(
+                std::path::Path::new(&model_dir)
+                    .join(name)
+                    .display()
+                    .to_string(),
+            )
+        }
+    };
+    let first_stage_file = if quantized {
+        "first_stage_q4k.gguf"
+    } else {
+        "first_stage.safetensors"
+    };
+    let first_stage_meta = local_file("first_stage.meta.json");
+    let first_stage_weights = local_file(first_stage_file);
+    let second_stage_weights = local_file("second_stage.safetensors");
+    let encodec_weights = local_file("encodec_24khz.safetensors");
+    let spk_emb = local_file("spk_emb.safetensors");
+    if !model_dir.is_empty() {
+        let required: Vec<std::path::PathBuf> = [
+            &first_stage_meta,
+            &first_stage_weights,
+            &second_stage_weights,
+            &encodec_weights,
+            &spk_emb,
+        ]
+        .iter()
+        .filter_map(|file| file.as_ref().map(std::path::PathBuf::from))
+        .collect();
+        require_files("metavoice", &required)?;
+    }
+
     if seed.is_none() {
         seed = Some(rand::random());
     }
@@ -75,8 +109,7 @@ pub async fn metavoice(prompt: String) -> Result<Bytes, Error> {
     };
 
     let device = candle_examples::device(cpu)?;
-    let api = Api::new()?;
-    let repo = api.model("lmz/candle-metavoice".to_string());
+    let repo = Api::new()?.model("lmz/candle-metavoice".to_string());
     let first_stage_meta = match &first_stage_meta {
         Some(w) => std::path::PathBuf::from(w),
         None => repo.get("first_stage.meta.json")?,
@@ -8,7 +8,7 @@ extern crate accelerate_src;
 use anyhow::{Error as E, Result};
 use safetensors::tensor::View;
 use std::io::Write;
-use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::mpsc::Sender;
 use tracing_chrome::ChromeLayerBuilder;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -16,15 +16,16 @@ use tracing_subscriber::util::SubscriberInitExt;
 use candle_transformers::models::mistral::{Config, Model as Mistral};
 use candle_transformers::models::quantized_mistral::Model as QMistral;
 
+use crate::llm_backend::GenerationParams;
+use crate::model_files::{require_files, ModelFiles};
+use crate::sampling::Sampler;
+use crate::stop_sequences::{StopCheck, StopMatcher};
 use candle_core::{DType, Device, Tensor};
 use candle_examples::token_output_stream::TokenOutputStream;
 use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
 use hf_hub::{api::sync::Api, Repo, RepoType};
 use log::{debug, info};
-use std::sync::Arc;
 use tokenizers::Tokenizer;
-use tokio::sync::Mutex;
 
 enum Model {
     Mistral(Mistral),
@@ -35,38 +36,42 @@ struct TextGeneration {
     model: Model,
     device: Device,
     tokenizer: TokenOutputStream,
-    logits_processor: LogitsProcessor,
-    repeat_penalty: f32,
-    repeat_last_n: usize,
-    internal_token_sender: Sender<String>,
+    sampler: Option<Sampler>,
+    stop_sequences: Vec<String>,
+    internal_token_sender: Option<Sender<String>>,
 }
 
 impl TextGeneration {
-    #[allow(clippy::too_many_arguments)]
-    fn new(
-        model: Model,
-        tokenizer: Tokenizer,
-        seed: u64,
-        temp: Option<f64>,
-        top_p: Option<f64>,
-        repeat_penalty: f32,
-        repeat_last_n: usize,
-        device: &Device,
-        internal_token_sender: Sender<String>,
-    ) -> Self {
-        let logits_processor = LogitsProcessor::new(seed, temp, top_p);
+    fn new(model: Model, tokenizer: Tokenizer, device: &Device) -> Self {
         Self {
             model,
             tokenizer: TokenOutputStream::new(tokenizer),
-            logits_processor,
-            repeat_penalty,
-            repeat_last_n,
+            sampler: None,
+            stop_sequences: Vec::new(),
             device: device.clone(),
-            internal_token_sender,
+            internal_token_sender: None,
         }
     }
 
+    /// Set up sampling and the token channel for the next request, the model stays loaded.
+    fn configure(&mut self, params: &GenerationParams, internal_token_sender: Sender<String>) {
+        self.sampler = Some(Sampler::new(params));
+        self.stop_sequences = params.stop.clone();
+        self.internal_token_sender = Some(internal_token_sender);
+    }
+
     async fn run(&mut self, prompt: &str, sample_len: usize) -> Result<()> {
+        // taken for this run only, dropping it closes the channel when generation ends
+        let internal_token_sender = match self.internal_token_sender.take() {
+            Some(sender) => sender,
+            None => anyhow::bail!("no token channel configured for generation"),
+        };
+        let mut sampler = match self.sampler.take() {
+            Some(sampler) => sampler,
+            None => anyhow::bail!("no sampler configured for generation"),
+        };
+        let mut stop_matcher = StopMatcher::new(&self.stop_sequences);
+        let mut stopped = false;
         let verbose_prompt: bool = false;
         let clear_kv_cache = true;
         if clear_kv_cache {
@@ -199,27 +204,33 @@ impl TextGeneration {
                 }
             }
 
-            let logits = if self.repeat_penalty == 1. {
-                logits
-            } else {
-                let start_at = tokens.len().saturating_sub(self.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    self.repeat_penalty,
-                    &tokens[start_at..],
-                )?
-            };
-
-            let next_token = self.logits_processor.sample(&logits)?;
+            let next_token = sampler.sample(&logits, &tokens)?;
             tokens.push(next_token);
             if next_token == eos_token {
                 break;
             }
             if let Some(t) = self.tokenizer.next_token(next_token)? {
-                self.internal_token_sender
-                    .send(t.clone())
-                    .await
-                    .expect("Failed to send token internally");
+                let (text, stop) = match stop_matcher.push(&t) {
+                    StopCheck::Continue(text) => (text, false),
+                    StopCheck::Stop(text) => (text, true),
+                };
+                if !text.is_empty() && internal_token_sender.send(text).await.is_err() {
+                    debug!("Token receiver closed, stopping generation");
+                    break;
+                }
+                if stop {
+                    debug!("Stop sequence reached, stopping generation");
+                    stopped = true;
+                    break;
+                }
+            }
+        }
+
+        // text held back as a possible stop sequence start
+        if !stopped {
+            let rest = stop_matcher.flush();
+            if !rest.is_empty() {
+                let _ = internal_token_sender.send(rest).await;
             }
         }
 
@@ -227,152 +238,141 @@ impl TextGeneration {
     }
 }
 
-pub fn mistral(
-    prompt: String,
-    sample_len: usize,
-    temperature: f64,
-    quantized: bool,
-    model_id: Option<String>,
-    external_sender: Sender<String>,
-) -> Result<()> {
-    let cpu = false;
-    let tracing = false;
-    let use_flash_attn = false;
-    let top_p: Option<f64> = None;
-    let seed = rand::random();
-    let revision: String = "main".to_string();
-    let tokenizer_file: Option<String> = None;
-    let weight_files: Option<String> = None;
-    let repeat_penalty = 1.1;
-    let repeat_last_n = (sample_len / 4) + prompt.len();
-
-    let _guard = if tracing {
-        let (chrome_layer, guard) = ChromeLayerBuilder::new().build();
-        tracing_subscriber::registry().with(chrome_layer).init();
-        Some(guard)
-    } else {
-        None
-    };
-    debug!(
-        "avx: {}, neon: {}, simd128: {}, f16c: {}",
-        candle_core::utils::with_avx(),
-        candle_core::utils::with_neon(),
-        candle_core::utils::with_simd128(),
-        candle_core::utils::with_f16c()
-    );
-    info!(
-        "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
-        temperature, repeat_penalty, repeat_last_n
-    );
-
-    let start = std::time::Instant::now();
-    let api = Api::new()?;
-    let model_id = match &model_id {
-        Some(model_id) => {
-            if model_id.is_empty() || model_id.to_string() == "auto" {
-                if quantized {
-                    "lmz/candle-mistral".to_string()
-                } else {
-                    "mistralai/Mistral-7B-Instruct-v0.2".to_string()
-                }
-            } else if model_id.to_lowercase() == "7b-it" {
-                "mistralai/Mistral-7B-Instruct-v0.2".to_string()
-            } else if model_id.to_lowercase() == "7b" {
-                "mistralai/Mistral-7B-v0.1".to_string()
-            } else {
-                model_id.to_string()
-            }
-        }
-        None => {
-            if quantized {
-                "lmz/candle-mistral".to_string()
+/// Mistral model and tokenizer loaded once and kept resident between generation requests.
+pub struct MistralModel {
+    pipeline: TextGeneration,
+}
+
+impl MistralModel {
+    pub fn load(
+        quantized: bool,
+        model_id: Option<String>,
+        model_files: &ModelFiles,
+    ) -> Result<Self> {
+        let cpu = false;
+        let tracing = false;
+        let use_flash_attn = false;
+        let revision: String = "main".to_string();
+
+        let _guard = if tracing {
+            let (chrome_layer, guard) = ChromeLayerBuilder::new().build();
+            tracing_subscriber::registry().with(chrome_layer).init();
+            Some(guard)
+        } else {
+            None
+        };
+        debug!(
+            "avx: {}, neon: {}, simd128: {}, f16c: {}",
+            candle_core::utils::with_avx(),
+            candle_core::utils::with_neon(),
+            candle_core::utils::with_simd128(),
+            candle_core::utils::with_f16c()
+        );
+
+        let start = std::time::Instant::now();
+        let (tokenizer_filename, filenames) = if model_files.is_local() {
+            // offline, load only from the given local paths
+            let gguf_name = if quantized {
+                Some("model-q4k.gguf")
             } else {
-                "mistralai/Mistral-7B-Instruct-v0.2".to_string()
-            }
-        }
-    };
-
-    let repo = api.repo(Repo::with_revision(model_id, RepoType::Model, revision));
-    let tokenizer_filename = match tokenizer_file {
-        Some(file) => std::path::PathBuf::from(file),
-        None => repo.get("tokenizer.json")?,
-    };
-    let filenames = match weight_files {
-        Some(files) => files
-            .split(',')
-            .map(std::path::PathBuf::from)
-            .collect::<Vec<_>>(),
-        None => {
-            if quantized {
+                None
+            };
+            let tokenizer_filename = model_files.tokenizer();
+            let filenames = model_files.weights(gguf_name)?;
+            let mut required = vec![tokenizer_filename.clone()];
+            required.extend(filenames.iter().cloned());
+            require_files("mistral", &required)?;
+            (tokenizer_filename, filenames)
+        } else {
+            let api = Api::new()?;
+            let model_id = match &model_id {
+                Some(model_id) => {
+                    if model_id.is_empty() || model_id.to_string() == "auto" {
+                        if quantized {
+                            "lmz/candle-mistral".to_string()
+                        } else {
+                            "mistralai/Mistral-7B-Instruct-v0.2".to_string()
+                        }
+                    } else if model_id.to_lowercase() == "7b-it" {
+                        "mistralai/Mistral-7B-Instruct-v0.2".to_string()
+                    } else if model_id.to_lowercase() == "7b" {
+                        "mistralai/Mistral-7B-v0.1".to_string()
+                    } else {
+                        model_id.to_string()
+                    }
+                }
+                None => {
+                    if quantized {
+                        "lmz/candle-mistral".to_string()
+                    } else {
+                        "mistralai/Mistral-7B-Instruct-v0.2".to_string()
+                    }
+                }
+            };
+
+            let repo = api.repo(Repo::with_revision(model_id, RepoType::Model, revision));
+            let tokenizer_filename = repo.get("tokenizer.json")?;
+            let filenames = if quantized {
                 vec![repo.get("model-q4k.gguf")?]
             } else {
                 candle_examples::hub_load_safetensors(&repo, "model.safetensors.index.json")?
-            }
-        }
-    };
-    info!("retrieved the files in {:?}", start.elapsed());
-    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-
-    let start = std::time::Instant::now();
-    let config = Config::config_7b_v0_1(use_flash_attn);
-    let device = candle_examples::device(cpu)?;
-    let (model, device) = if quantized {
-        let filename = &filenames[0];
-        let vb =
-            candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename, &device)?;
-        let model = QMistral::new(&config, vb)?;
-        (Model::Quantized(model), device)
-    } else {
-        let dtype = if device.is_cuda() {
-            DType::BF16
+            };
+            (tokenizer_filename, filenames)
+        };
+        info!("retrieved the files in {:?}", start.elapsed());
+        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+
+        let start = std::time::Instant::now();
+        let config = Config::config_7b_v0_1(use_flash_attn);
+        let device = candle_examples::device(cpu)?;
+        let (model, device) = if quantized {
+            let filename = &filenames[0];
+            let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(
+                filename, &device,
+            )?;
+            let model = QMistral::new(&config, vb)?;
+            (Model::Quantized(model), device)
         } else {
-            DType::F32
+            let dtype = if device.is_cuda() {
+                DType::BF16
+            } else {
+                DType::F32
+            };
+            let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
+            let model = Mistral::new(&config, vb)?;
+            (Model::Mistral(model), device)
         };
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
-        let model = Mistral::new(&config, vb)?;
-        (Model::Mistral(model), device)
-    };
-
-    info!("loaded the model in {:?}", start.elapsed());
-
-    let (internal_sender, mut internal_receiver) = mpsc::channel(32768);
-
-    // Pass both the internal and external senders to TextGeneration
-    let pipeline = TextGeneration::new(
-        model,
-        tokenizer,
-        seed,              // seed
-        Some(temperature), // temp
-        top_p,             // top_p
-        repeat_penalty,    // repeat_penalty
-        repeat_last_n,     // repeat_last_n
-        &device,
-        internal_sender,
-    );
-
-    let pipeline = Arc::new(Mutex::new(pipeline));
-
-    // Start the text generation in a separate thread
-    let pipeline_clone = pipeline.clone();
-    let prompt_clone = prompt.clone();
-    tokio::spawn(async move {
-        let mut pipeline = pipeline_clone.lock().await;
-        match pipeline.run(&prompt_clone, sample_len).await {
-            Ok(_) => {}
-            Err(e) => log::error!("Failed to run the pipeline: {}", e),
-        }
-    });
-
-    // Set up a thread to listen on the internal receiver and forward messages to the external sender
-    let external_sender_clone = external_sender.clone();
-    tokio::spawn(async move {
-        while let Some(token) = internal_receiver.recv().await {
-            if let Err(e) = external_sender_clone.send(token).await {
-                log::error!("Failed to send token externally: {}", e);
-                break;
-            }
-        }
-    });
 
-    Ok(())
-}
\ No newline at end of file
+        info!("loaded the model in {:?}", start.elapsed());
+
+        Ok(MistralModel {
+            pipeline: TextGeneration::new(model, tokenizer, &device),
+        })
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        self.pipeline.tokenizer.tokenizer()
+    }
+
+    /// Generate a response to the prompt, streaming tokens over the external sender.
+    pub async fn generate(
+        &mut self,
+        prompt: &str,
+        params: &GenerationParams,
+        external_sender: Sender<String>,
+    ) -> Result<()> {
+        info!(
+            "temp: {:.2} top-p: {:.2} top-k: {} min-p: {:.2} repeat-penalty: {:.2} repeat-last-n: {} seed: {}",
+            params.temperature,
+            params.top_p,
+            params.top_k,
+            params.min_p,
+            params.repeat_penalty,
+            params.repeat_last_n,
+            params.seed
+        );
+
+        self.pipeline.configure(params, external_sender);
+        self.pipeline.run(prompt, params.max_tokens).await
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * capture_buffer.rs
+ * -----------------
+ * Rolling in-memory buffer of the last N seconds of raw captured packets,
+ * dumped to a .ts or .pcap file when a stream alert fires so the evidence
+ * around an error is kept for later analysis.
+*/
+
+use crate::current_unix_timestamp_ms;
+use anyhow::anyhow;
+use log::{debug, info};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// pcap file constants, LINKTYPE_ETHERNET matches what libpcap hands us from the capture device
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65535;
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureFormat {
+    Ts,
+    Pcap,
+}
+
+impl FromStr for CaptureFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "ts" => Ok(CaptureFormat::Ts),
+            "pcap" => Ok(CaptureFormat::Pcap),
+            _ => Err(anyhow!(
+                "unknown capture buffer format {}, expected ts or pcap",
+                format
+            )),
+        }
+    }
+}
+
+impl CaptureFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CaptureFormat::Ts => "ts",
+            CaptureFormat::Pcap => "pcap",
+        }
+    }
+}
+
+pub struct CaptureBuffer {
+    packets: VecDeque<(u64, Arc<Vec<u8>>)>,
+    duration_ms: u64,
+    payload_offset: usize,
+    format: CaptureFormat,
+    output_dir: PathBuf,
+    cooldown_ms: u64,
+    last_dump_ms: u64,
+}
+
+impl CaptureBuffer {
+    pub fn new(
+        duration_secs: u64,
+        payload_offset: usize,
+        format: CaptureFormat,
+        output_dir: String,
+    ) -> Self {
+        CaptureBuffer {
+            packets: VecDeque::new(),
+            duration_ms: duration_secs * 1000,
+            payload_offset,
+            format,
+            output_dir: PathBuf::from(output_dir),
+            // don't write more than one file per buffer window, the next one would overlap
+            cooldown_ms: duration_secs * 1000,
+            last_dump_ms: 0,
+        }
+    }
+
+    /// Add a raw packet to the buffer, expiring anything older than the buffer window.
+    pub fn push(&mut self, packet: Arc<Vec<u8>>) {
+        let now = current_unix_timestamp_ms().unwrap_or(0);
+        self.packets.push_back((now, packet));
+        while let Some((ts, _)) = self.packets.front() {
+            if now.saturating_sub(*ts) > self.duration_ms {
+                self.packets.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Dump the buffered packets to a file named after the alert reason.
+    /// Returns the file path, or None if we are still within the cooldown of the last dump.
+    pub fn dump(&mut self, reason: &str) -> std::io::Result<Option<PathBuf>> {
+        let now = current_unix_timestamp_ms().unwrap_or(0);
+        if self.packets.is_empty()
+            || (self.last_dump_ms > 0 && now.saturating_sub(self.last_dump_ms) < self.cooldown_ms)
+        {
+            debug!(
+                "CaptureBuffer: skipping dump for {}, {} packets buffered",
+                reason,
+                self.packets.len()
+            );
+            return Ok(None);
+        }
+        self.last_dump_ms = now;
+
+        fs::create_dir_all(&self.output_dir)?;
+        let reason_name: String = reason
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        let file_name = format!(
+            "{}_{}.{}",
+            chrono::Local::now().format("%Y%m%d-%H%M%S%.3f"),
+            reason_name,
+            self.format.extension()
+        );
+        let path = self.output_dir.join(file_name);
+
+        let mut writer = BufWriter::new(File::create(&path)?);
+        match self.format {
+            CaptureFormat::Ts => self.write_ts(&mut writer)?,
+            CaptureFormat::Pcap => self.write_pcap(&mut writer)?,
+        }
+        writer.flush()?;
+
+        info!(
+            "CaptureBuffer: wrote {} packets ({} seconds) for {} to {}",
+            self.packets.len(),
+            self.duration_ms / 1000,
+            reason,
+            path.display()
+        );
+
+        Ok(Some(path))
+    }
+
+    // Raw MpegTS payload of each packet, headers stripped at the payload offset
+    fn write_ts<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for (_, packet) in self.packets.iter() {
+            if packet.len() > self.payload_offset {
+                writer.write_all(&packet[self.payload_offset..])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Full packets with the libpcap file header so they open in wireshark / tcpdump
+    fn write_pcap<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+        writer.write_all(&0i32.to_le_bytes())?; // thiszone
+        writer.write_all(&0u32.to_le_bytes())?; // sigfigs
+        writer.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        writer.write_all(&PCAP_LINKTYPE_ETHERNET.to_le_bytes())?;
+
+        for (ts, packet) in self.packets.iter() {
+            let ts_sec = (ts / 1000) as u32;
+            let ts_usec = ((ts % 1000) * 1000) as u32;
+            let len = packet.len() as u32;
+            writer.write_all(&ts_sec.to_le_bytes())?;
+            writer.write_all(&ts_usec.to_le_bytes())?;
+            writer.write_all(&len.to_le_bytes())?;
+            writer.write_all(&len.to_le_bytes())?;
+            writer.write_all(packet)?;
+        }
+        Ok(())
+    }
+}
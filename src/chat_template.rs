@@ -0,0 +1,356 @@
+/*
+ * chat_template.rs
+ * ----------------
+ * Chat templates as data, built-in presets for the local models, TOML turn
+ * templates and the Jinja chat_template from a model's tokenizer_config.json.
+*/
+
+use crate::openai_api::Message;
+use anyhow::{anyhow, Result};
+use minijinja::{context, Environment, ErrorKind};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Text around each message by role, the last assistant message is left open for the model.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct TurnTemplate {
+    pub system_prefix: String,
+    pub system_suffix: String,
+    pub user_prefix: String,
+    pub user_suffix: String,
+    pub assistant_prefix: String,
+    pub assistant_suffix: String,
+    /// Strings the model emits when it starts another turn, generation stops there.
+    pub stop: Vec<String>,
+}
+
+impl TurnTemplate {
+    fn new(
+        system: (&str, &str),
+        user: (&str, &str),
+        assistant: (&str, &str),
+        stop: &[&str],
+    ) -> Self {
+        TurnTemplate {
+            system_prefix: system.0.to_string(),
+            system_suffix: system.1.to_string(),
+            user_prefix: user.0.to_string(),
+            user_suffix: user.1.to_string(),
+            assistant_prefix: assistant.0.to_string(),
+            assistant_suffix: assistant.1.to_string(),
+            stop: stop.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn render(&self, messages: &[Message]) -> String {
+        let mut formatted_history = String::new();
+        for (index, message) in messages.iter().enumerate() {
+            let is_last = index == messages.len() - 1;
+            // remove <|im_end|> from anywhere in message
+            let content = message.content.replace("<|im_end|>", "");
+            let (prefix, suffix) = match message.role.as_str() {
+                "system" => (&self.system_prefix, &self.system_suffix),
+                "user" => (&self.user_prefix, &self.user_suffix),
+                "assistant" => (&self.assistant_prefix, &self.assistant_suffix),
+                _ => continue,
+            };
+            formatted_history += prefix;
+            formatted_history += &content;
+            // the last assistant message is the one the model continues
+            if !(is_last && message.role == "assistant") {
+                formatted_history += suffix;
+            }
+        }
+        // open the assistant turn when the conversation ends on another role
+        if messages
+            .last()
+            .map(|message| message.role != "assistant")
+            .unwrap_or(true)
+        {
+            formatted_history += &self.assistant_prefix;
+        }
+        formatted_history
+    }
+}
+
+// The system messages moved to the start of the first user turn
+fn fold_system_messages(messages: &[Message]) -> Vec<Message> {
+    let system: Vec<&str> = messages
+        .iter()
+        .filter(|m| m.role == "system")
+        .map(|m| m.content.as_str())
+        .collect();
+    let mut folded: Vec<Message> = messages
+        .iter()
+        .filter(|m| m.role != "system")
+        .cloned()
+        .collect();
+    let instructions = system.join("\n\n");
+    match folded.iter_mut().find(|m| m.role == "user") {
+        Some(user) => user.content = format!("{}\n\n{}", instructions, user.content),
+        None => folded.insert(
+            0,
+            Message {
+                role: "user".to_string(),
+                content: instructions,
+                ..Default::default()
+            },
+        ),
+    }
+    folded
+}
+
+/// How a conversation is turned into the raw prompt text for a local model.
+#[derive(Clone, Debug)]
+pub enum ChatTemplate {
+    Turns(TurnTemplate),
+    Jinja {
+        source: String,
+        bos_token: String,
+        eos_token: String,
+    },
+}
+
+/// Built-in chat format names.
+pub const PRESETS: &[&str] = &["", "llama2", "llama3", "phi3", "google", "chatml", "vicuna"];
+
+impl ChatTemplate {
+    /// Built-in template by chat format name.
+    pub fn preset(chat_format: &str) -> Option<Self> {
+        let turns = match chat_format {
+            "" => TurnTemplate::new(("", "\n"), ("", "\n"), ("", "\n"), &[]),
+            "llama2" => TurnTemplate::new(
+                ("<s>[INST] <<SYS>>\n", "\n<</SYS>> [/INST]</s>\n"),
+                ("<s>[INST] ", " [/INST]"),
+                (" ", "</s>\n"),
+                &["[INST]", "</s>"],
+            ),
+            "llama3" => TurnTemplate::new(
+                (
+                    "<|start_header_id|>system<|end_header_id|>\n\n",
+                    "<|eot_id|>",
+                ),
+                ("<|start_header_id|>user<|end_header_id|>\n\n", "<|eot_id|>"),
+                (
+                    "<|start_header_id|>assistant<|end_header_id|>\n\n",
+                    "<|eot_id|>",
+                ),
+                &["<|eot_id|>", "<|start_header_id|>"],
+            ),
+            "phi3" => TurnTemplate::new(
+                ("<|system|>\n", "<|end|>\n"),
+                ("<|user|>\n", "<|end|>\n"),
+                ("<|assistant|>\n", "<|end|>\n"),
+                &["<|end|>", "<|user|>"],
+            ),
+            // gemma has no system role, the instructions go in a user turn
+            "google" => TurnTemplate::new(
+                ("<start_of_turn>user\n", "<end_of_turn>\n"),
+                ("<start_of_turn>user\n", "<end_of_turn>\n"),
+                ("<start_of_turn>model\n", "<end_of_turn>\n"),
+                &["<end_of_turn>", "<start_of_turn>"],
+            ),
+            "chatml" => TurnTemplate::new(
+                ("<|im_start|>system\n", "<|im_end|>\n"),
+                ("<|im_start|>user\n", "<|im_end|>\n"),
+                ("<|im_start|>assistant\n", "<|im_end|>\n"),
+                &["<|im_end|>", "<|im_start|>"],
+            ),
+            "vicuna" => TurnTemplate::new(
+                ("", "\n\n"),
+                ("USER: ", "\n"),
+                ("ASSISTANT: ", "</s>\n"),
+                &["USER:", "</s>"],
+            ),
+            _ => return None,
+        };
+        Some(ChatTemplate::Turns(turns))
+    }
+
+    /// Load a chat format, a preset name, a TOML turn template, a Jinja template
+    /// or a tokenizer_config.json with a `chat_template`.
+    pub fn load(chat_format: &str) -> Result<Self> {
+        if let Some(template) = ChatTemplate::preset(chat_format) {
+            return Ok(template);
+        }
+        let path = Path::new(chat_format);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => {
+                let turns: TurnTemplate = toml::from_str(&std::fs::read_to_string(path)?)?;
+                Ok(ChatTemplate::Turns(turns))
+            }
+            Some("json") => ChatTemplate::from_tokenizer_config(path),
+            Some("jinja") | Some("j2") => Ok(ChatTemplate::Jinja {
+                source: std::fs::read_to_string(path)?,
+                bos_token: String::new(),
+                eos_token: String::new(),
+            }),
+            _ => Err(anyhow!(
+                "unknown chat format {}, use one of {} or a .toml, .jinja or tokenizer_config.json file",
+                chat_format,
+                PRESETS[1..].join(", ")
+            )),
+        }
+    }
+
+    /// The `chat_template` from a huggingface tokenizer_config.json.
+    pub fn from_tokenizer_config(path: &Path) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_reader(std::fs::File::open(path)?)?;
+        let source = match json.get("chat_template") {
+            Some(serde_json::Value::String(source)) => source.clone(),
+            // a list of named templates, use the default one
+            Some(serde_json::Value::Array(templates)) => templates
+                .iter()
+                .find(|t| t.get("name").and_then(|n| n.as_str()) == Some("default"))
+                .and_then(|t| t.get("template"))
+                .and_then(|t| t.as_str())
+                .map(|t| t.to_string())
+                .ok_or_else(|| anyhow!("no default chat_template in {}", path.display()))?,
+            _ => return Err(anyhow!("no chat_template in {}", path.display())),
+        };
+        // special tokens are either plain strings or added token objects
+        let token = |name: &str| match json.get(name) {
+            Some(serde_json::Value::String(token)) => token.clone(),
+            Some(value) => value
+                .get("content")
+                .and_then(|c| c.as_str())
+                .unwrap_or("")
+                .to_string(),
+            None => String::new(),
+        };
+        Ok(ChatTemplate::Jinja {
+            source,
+            bos_token: token("bos_token"),
+            eos_token: token("eos_token"),
+        })
+    }
+
+    /// Format the conversation as the prompt text for the model.
+    pub fn render(&self, messages: &[Message]) -> Result<String> {
+        match self {
+            ChatTemplate::Turns(turns) => Ok(turns.render(messages)),
+            ChatTemplate::Jinja {
+                source,
+                bos_token,
+                eos_token,
+            } => {
+                // an empty assistant message at the end is our open turn, jinja templates
+                // open it themselves with add_generation_prompt
+                let messages = match messages.last() {
+                    Some(last) if last.role == "assistant" && last.content.is_empty() => {
+                        &messages[..messages.len() - 1]
+                    }
+                    _ => messages,
+                };
+                let add_generation_prompt = messages
+                    .last()
+                    .map(|message| message.role != "assistant")
+                    .unwrap_or(true);
+
+                let mut env = Environment::new();
+                env.add_function(
+                    "raise_exception",
+                    |message: String| -> Result<String, minijinja::Error> {
+                        Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+                    },
+                );
+                env.add_template("chat", source)?;
+                let template = env.get_template("chat")?;
+                let render = |messages: &[Message]| {
+                    template.render(context! {
+                        messages => messages,
+                        bos_token => bos_token,
+                        eos_token => eos_token,
+                        add_generation_prompt => add_generation_prompt,
+                    })
+                };
+                match render(messages) {
+                    Ok(prompt) => Ok(prompt),
+                    // templates without a system role, like gemma's, raise an exception on it
+                    Err(e)
+                        if e.kind() == ErrorKind::InvalidOperation
+                            && messages.iter().any(|m| m.role == "system") =>
+                    {
+                        Ok(render(&fold_system_messages(messages))?)
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    }
+
+    /// Stop sequences ending the model's turn.
+    pub fn stop_sequences(&self) -> Vec<String> {
+        match self {
+            ChatTemplate::Turns(turns) => turns.stop.clone(),
+            ChatTemplate::Jinja { eos_token, .. } => {
+                if eos_token.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![eos_token.clone()]
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // The chat_template of gemma's tokenizer_config.json
+    const GEMMA_TEMPLATE: &str = "{{ bos_token }}{% if messages[0]['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if (message['role'] == 'assistant') %}{% set role = 'model' %}{% else %}{% set role = message['role'] %}{% endif %}{{ '<start_of_turn>' + role + '\n' + message['content'] | trim + '<end_of_turn>\n' }}{% endfor %}{% if add_generation_prompt %}{{'<start_of_turn>model\n'}}{% endif %}";
+
+    fn gemma() -> ChatTemplate {
+        ChatTemplate::Jinja {
+            source: GEMMA_TEMPLATE.to_string(),
+            bos_token: "<bos>".to_string(),
+            eos_token: "<eos>".to_string(),
+        }
+    }
+
+    #[test]
+    fn system_message_folded_into_first_user_turn() {
+        let prompt = gemma()
+            .render(&[
+                message("system", "Be brief."),
+                message("user", "Hi"),
+                message("assistant", "Hello."),
+                message("user", "Tell a story."),
+                message("assistant", ""),
+            ])
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "<bos><start_of_turn>user\nBe brief.\n\nHi<end_of_turn>\n\
+             <start_of_turn>model\nHello.<end_of_turn>\n\
+             <start_of_turn>user\nTell a story.<end_of_turn>\n\
+             <start_of_turn>model\n"
+        );
+    }
+
+    #[test]
+    fn system_only_conversation_becomes_a_user_turn() {
+        let prompt = gemma().render(&[message("system", "Say hi.")]).unwrap();
+        assert_eq!(
+            prompt,
+            "<bos><start_of_turn>user\nSay hi.<end_of_turn>\n<start_of_turn>model\n"
+        );
+    }
+
+    #[test]
+    fn other_template_errors_are_returned() {
+        // roles that don't alternate fail even without a system message
+        let result = gemma().render(&[message("user", "Hi"), message("user", "Again")]);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,316 @@
+/*
+ * config.rs
+ * ---------
+ * TOML or YAML configuration file with named profiles in place of long command
+ * lines. Settings become command line options placed before the real ones, so
+ * command line flags and environment variables still take precedence and clap
+ * validates every value. Prompts, voices, the poll interval and Stable Diffusion
+ * settings are reloaded when the file changes.
+*/
+
+use crate::args::Args;
+use anyhow::{anyhow, Result};
+use clap::{CommandFactory, FromArgMatches};
+use log::info;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// A setting value, passed to its command line option as text.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+enum ConfigValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl ConfigValue {
+    fn to_text(&self) -> String {
+        match self {
+            ConfigValue::Bool(value) => value.to_string(),
+            ConfigValue::Integer(value) => value.to_string(),
+            ConfigValue::Float(value) => value.to_string(),
+            ConfigValue::Text(value) => value.clone(),
+        }
+    }
+}
+
+/// Settings for every profile, then the profiles overriding them.
+#[derive(Deserialize, Default)]
+struct ConfigDocument {
+    #[serde(default)]
+    defaults: BTreeMap<String, ConfigValue>,
+    #[serde(default)]
+    profiles: BTreeMap<String, BTreeMap<String, ConfigValue>>,
+}
+
+/// A configuration file and the profile running from it.
+pub struct ConfigFile {
+    path: String,
+    profile: String,
+    modified: Option<SystemTime>,
+    /// Args of the last good load, reloads only apply what changed since
+    loaded: Option<Args>,
+}
+
+impl ConfigFile {
+    pub fn new(path: &str, profile: &str) -> Self {
+        ConfigFile {
+            path: path.to_string(),
+            profile: profile.to_string(),
+            modified: None,
+            loaded: None,
+        }
+    }
+
+    /// Parse the command line with the profile settings filled in, errors on unknown or invalid settings.
+    pub fn load(&mut self) -> Result<Args> {
+        self.load_with(std::env::args_os().collect())
+    }
+
+    fn load_with(&mut self, args_os: Vec<OsString>) -> Result<Args> {
+        self.modified = std::fs::metadata(&self.path)?.modified().ok();
+        let settings = self.read_profile()?;
+        let mut command_line = args_os.iter().take(1).cloned().collect::<Vec<_>>();
+        command_line.extend(self.options(&settings)?.into_iter().map(Into::into));
+        command_line.extend(args_os.into_iter().skip(1));
+        // the real command line comes last and overrides the file
+        let matches = Args::command()
+            .args_override_self(true)
+            .try_get_matches_from(command_line)
+            .map_err(|e| anyhow!("{}: {}", self.path, e))?;
+        let args = Args::from_arg_matches(&matches).map_err(|e| anyhow!("{}: {}", self.path, e))?;
+        self.loaded = Some(args.clone());
+        Ok(args)
+    }
+
+    /// Reparse when the file has changed since it was last loaded, None when unchanged.
+    /// Returns the args of the previous load with the reloaded ones.
+    pub fn reload_if_changed(&mut self) -> Result<Option<(Args, Args)>> {
+        let modified = std::fs::metadata(&self.path)?.modified().ok();
+        if modified == self.modified {
+            return Ok(None);
+        }
+        // a failed reload is not retried until the file changes again
+        self.modified = modified;
+        info!("Config {} changed, reloading.", self.path);
+        let previous = self.loaded.clone();
+        let reloaded = self.load()?;
+        Ok(previous.map(|previous| (previous, reloaded)))
+    }
+
+    fn read_profile(&self) -> Result<BTreeMap<String, ConfigValue>> {
+        let text = std::fs::read_to_string(&self.path)?;
+        let document: ConfigDocument = match Path::new(&self.path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+        {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&text)?,
+            Some("toml") => toml::from_str(&text)?,
+            _ => {
+                return Err(anyhow!(
+                    "config file {} must be .toml, .yaml or .yml",
+                    self.path
+                ))
+            }
+        };
+        let mut settings = document.defaults;
+        if !self.profile.is_empty() {
+            match document.profiles.get(&self.profile) {
+                Some(profile) => settings.extend(profile.clone()),
+                None => {
+                    return Err(anyhow!(
+                        "profile {} is not in {}, the profiles are {}",
+                        self.profile,
+                        self.path,
+                        document
+                            .profiles
+                            .keys()
+                            .cloned()
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                }
+            }
+        }
+        Ok(settings)
+    }
+
+    // Command line options for the settings, skipping those set in the environment
+    fn options(&self, settings: &BTreeMap<String, ConfigValue>) -> Result<Vec<String>> {
+        let command = Args::command();
+        let mut options = Vec::new();
+        for (key, value) in settings {
+            let id = key.replace('-', "_");
+            if id == "config" || id == "profile" {
+                return Err(anyhow!("{} can't be set in the config file", key));
+            }
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_id() == id.as_str())
+                .ok_or_else(|| anyhow!("unknown setting {} in {}", key, self.path))?;
+            // the environment overrides the file, an option given here would override it
+            if let Some(env) = arg.get_env() {
+                if std::env::var_os(env).is_some() {
+                    continue;
+                }
+            }
+            let long = arg
+                .get_long()
+                .ok_or_else(|| anyhow!("{} has no command line option", key))?;
+            let text = value.to_text();
+            if arg.get_action().takes_values() {
+                options.push(format!("--{}={}", long, text));
+            } else if text == "true" {
+                options.push(format!("--{}", long));
+            } else if text != "false" {
+                return Err(anyhow!("{} in {} must be true or false", key, self.path));
+            }
+        }
+        Ok(options)
+    }
+}
+
+// Copy the listed fields the reload changed, collecting their names
+macro_rules! hot_fields {
+    ($args:ident, $previous:ident, $reloaded:ident, $changed:ident, $($field:ident),*) => {
+        $(
+            if $previous.$field != $reloaded.$field {
+                $args.$field = $reloaded.$field.clone();
+                $changed.push(stringify!($field));
+            }
+        )*
+    };
+}
+
+/// Take the settings that can change while running from reloaded args, returns the changed names.
+/// Only settings that differ from the previous load are taken, so changes made from the control
+/// panel or the schedule stay until the file changes them. Everything else needs a restart.
+pub fn apply_hot_reload(args: &mut Args, previous: &Args, reloaded: &Args) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    hot_fields!(
+        args,
+        previous,
+        reloaded,
+        changed,
+        system_prompt,
+        query,
+        greeting,
+        twitch_prompt,
+        assistant_image_prompt,
+        mimic3_voice,
+        poll_interval,
+        temperature,
+        sd_model,
+        sd_custom_model,
+        sd_width,
+        sd_height,
+        sd_n_steps,
+        sd_text_min,
+        sd_max_length,
+        image_alignment
+    );
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A config file in the temp directory, removed when dropped
+    struct TempConfig(String);
+
+    impl TempConfig {
+        fn new(name: &str, text: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("rsllm-{}-{}.toml", name, std::process::id()))
+                .display()
+                .to_string();
+            std::fs::write(&path, text).unwrap();
+            TempConfig(path)
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn load(config: &TempConfig, profile: &str) -> Result<Args> {
+        ConfigFile::new(&config.0, profile).load_with(vec!["rsllm".into()])
+    }
+
+    const CONFIG: &str = r#"
+[defaults]
+max_tokens = 100
+system_prompt = "default prompt"
+
+[profiles.storyteller]
+system_prompt = "tell stories"
+daemon = true
+"#;
+
+    #[test]
+    fn profile_overrides_the_defaults() {
+        let config = TempConfig::new("profile", CONFIG);
+        let args = load(&config, "storyteller").unwrap();
+        assert_eq!(args.max_tokens, 100);
+        assert_eq!(args.system_prompt, "tell stories");
+        assert!(args.daemon);
+    }
+
+    #[test]
+    fn unknown_profile_lists_the_profiles() {
+        let config = TempConfig::new("unknown-profile", CONFIG);
+        let error = load(&config, "news").unwrap_err().to_string();
+        assert!(error.contains("profile news is not in"), "{}", error);
+        assert!(error.contains("storyteller"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        let config = TempConfig::new("unknown-field", "[defaults]\nno_such_setting = 1\n");
+        let error = load(&config, "").unwrap_err().to_string();
+        assert!(
+            error.contains("unknown setting no_such_setting"),
+            "{}",
+            error
+        );
+
+        let config = TempConfig::new("invalid-value", "[defaults]\nmax_tokens = \"many\"\n");
+        assert!(load(&config, "").is_err());
+
+        let config = TempConfig::new("invalid-flag", "[defaults]\ndaemon = \"yes\"\n");
+        assert!(load(&config, "").is_err());
+    }
+
+    #[test]
+    fn reload_takes_only_changed_hot_fields() {
+        let config = TempConfig::new("reload", CONFIG);
+        let previous = load(&config, "storyteller").unwrap();
+        let mut args = previous.clone();
+        // changed from the control panel while running
+        args.mimic3_voice = "en_US/vctk_low#p226".to_string();
+
+        std::fs::write(
+            &config.0,
+            CONFIG
+                .replace("max_tokens = 100", "max_tokens = 200")
+                .replace("tell stories", "tell tall tales"),
+        )
+        .unwrap();
+        let reloaded = load(&config, "storyteller").unwrap();
+        let changed = apply_hot_reload(&mut args, &previous, &reloaded);
+
+        assert_eq!(changed, ["system_prompt"]);
+        assert_eq!(args.system_prompt, "tell tall tales");
+        // max tokens needs a restart, the panel's voice stays until the file changes it
+        assert_eq!(args.max_tokens, 100);
+        assert_eq!(args.mimic3_voice, "en_US/vctk_low#p226");
+    }
+}
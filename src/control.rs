@@ -0,0 +1,325 @@
+/*
+ * control.rs
+ * ----------
+ * Control panel and REST API to steer the running daemon, producers can inject
+ * queries, change the system prompt, pause the story, switch voices and models
+ * and shut down without shell access. The main loop picks changes up each turn.
+*/
+
+use crate::openai_api::Message;
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Stable Diffusion models the control panel can switch to.
+pub const SD_MODELS: [&str; 5] = ["1.5", "2.1", "xl", "turbo", "Custom"];
+
+/// State of the show published by the main loop each turn.
+#[derive(Serialize, Clone, Default)]
+pub struct ControlStatus {
+    pub backend: String,
+    pub iterations: i32,
+    pub paused: bool,
+    pub system_prompt: String,
+    pub voice: String,
+    pub sd_model: String,
+    /// Segment of the schedule on air
+    pub segment: String,
+    /// Queued injected queries
+    pub queued_queries: usize,
+    /// Paragraphs waiting for images and speech
+    pub pipeline_queue: usize,
+}
+
+#[derive(Default)]
+struct Pending {
+    queries: VecDeque<String>,
+    system_prompt: Option<String>,
+    voice: Option<String>,
+    sd_model: Option<String>,
+}
+
+/// Changes requested over the API and the status of the main loop.
+pub struct Control {
+    pending: Mutex<Pending>,
+    status: Mutex<ControlStatus>,
+    /// Conversation as of the last turn /api/history was polled
+    history: Mutex<Vec<Message>>,
+    /// Set by /api/history, the main loop only copies the history when asked
+    history_wanted: AtomicBool,
+    paused: AtomicBool,
+    /// The main loop's running flag, cleared to shut down like Ctrl+C
+    running: Arc<AtomicBool>,
+    /// Speech comes from OpenAI TTS, which has no mimic3 voices to switch
+    oai_tts: bool,
+}
+
+impl Control {
+    pub fn new(running: Arc<AtomicBool>, oai_tts: bool) -> Self {
+        Control {
+            pending: Mutex::new(Pending::default()),
+            status: Mutex::new(ControlStatus::default()),
+            history: Mutex::new(Vec::new()),
+            history_wanted: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            running,
+            oai_tts,
+        }
+    }
+
+    pub fn push_query(&self, query: String) {
+        self.pending.lock().unwrap().queries.push_back(query);
+    }
+
+    /// Next injected query, these run even while paused.
+    pub fn take_query(&self) -> Option<String> {
+        self.pending.lock().unwrap().queries.pop_front()
+    }
+
+    pub fn take_system_prompt(&self) -> Option<String> {
+        self.pending.lock().unwrap().system_prompt.take()
+    }
+
+    pub fn take_voice(&self) -> Option<String> {
+        self.pending.lock().unwrap().voice.take()
+    }
+
+    pub fn take_sd_model(&self) -> Option<String> {
+        self.pending.lock().unwrap().sd_model.take()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn publish(&self, status: ControlStatus) {
+        *self.status.lock().unwrap() = status;
+    }
+
+    /// True once after /api/history was polled, the main loop then publishes the history.
+    pub fn history_wanted(&self) -> bool {
+        self.history_wanted.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn publish_history(&self, history: Vec<Message>) {
+        *self.history.lock().unwrap() = history;
+    }
+
+    pub fn status(&self) -> ControlStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        status.paused = self.is_paused();
+        status.queued_queries = self.pending.lock().unwrap().queries.len();
+        status
+    }
+}
+
+#[derive(Deserialize)]
+struct QueryRequest {
+    query: String,
+}
+
+#[derive(Deserialize)]
+struct SystemPromptRequest {
+    prompt: String,
+}
+
+#[derive(Deserialize)]
+struct VoiceRequest {
+    voice: String,
+}
+
+#[derive(Deserialize)]
+struct SdModelRequest {
+    model: String,
+}
+
+/// Body of the actions without fields. Requiring JSON means a cross-site page
+/// can't trigger them with a plain form or no-cors fetch, which skip the preflight.
+#[derive(Deserialize)]
+struct ActionRequest {}
+
+fn accepted(message: &str) -> Response {
+    (StatusCode::ACCEPTED, Json(json!({"status": message}))).into_response()
+}
+
+fn bad_request(message: &str) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({"error": message}))).into_response()
+}
+
+async fn index() -> Html<&'static str> {
+    Html(CONTROL_PAGE)
+}
+
+async fn status(State(control): State<Arc<Control>>) -> Json<ControlStatus> {
+    Json(control.status())
+}
+
+// The history published for the last poll, and ask the main loop for a fresh copy
+async fn history(State(control): State<Arc<Control>>) -> Json<Vec<Message>> {
+    control.history_wanted.store(true, Ordering::SeqCst);
+    Json(control.history.lock().unwrap().clone())
+}
+
+async fn query(State(control): State<Arc<Control>>, Json(request): Json<QueryRequest>) -> Response {
+    if request.query.trim().is_empty() {
+        return bad_request("query must not be empty");
+    }
+    info!("Control: query injected.");
+    control.push_query(request.query);
+    accepted("queued")
+}
+
+async fn system_prompt(
+    State(control): State<Arc<Control>>,
+    Json(request): Json<SystemPromptRequest>,
+) -> Response {
+    if request.prompt.trim().is_empty() {
+        return bad_request("prompt must not be empty");
+    }
+    info!("Control: system prompt changed.");
+    control.pending.lock().unwrap().system_prompt = Some(request.prompt);
+    accepted("system prompt changes next turn")
+}
+
+async fn pause(State(control): State<Arc<Control>>, Json(_): Json<ActionRequest>) -> Response {
+    info!("Control: story paused.");
+    control.set_paused(true);
+    accepted("paused")
+}
+
+async fn resume(State(control): State<Arc<Control>>, Json(_): Json<ActionRequest>) -> Response {
+    info!("Control: story resumed.");
+    control.set_paused(false);
+    accepted("resumed")
+}
+
+async fn voice(State(control): State<Arc<Control>>, Json(request): Json<VoiceRequest>) -> Response {
+    if request.voice.trim().is_empty() {
+        return bad_request("voice must not be empty");
+    }
+    if control.oai_tts {
+        return bad_request("voices are mimic3 voices, the OpenAI TTS voice can not be changed");
+    }
+    info!("Control: voice changed to {}.", request.voice);
+    control.pending.lock().unwrap().voice = Some(request.voice);
+    accepted("voice changes next turn")
+}
+
+async fn sd_model(
+    State(control): State<Arc<Control>>,
+    Json(request): Json<SdModelRequest>,
+) -> Response {
+    if !SD_MODELS.contains(&request.model.as_str()) {
+        return bad_request(&format!("model must be one of {}", SD_MODELS.join(", ")));
+    }
+    info!(
+        "Control: Stable Diffusion model changed to {}.",
+        request.model
+    );
+    control.pending.lock().unwrap().sd_model = Some(request.model);
+    accepted("model changes next turn")
+}
+
+async fn shutdown(State(control): State<Arc<Control>>, Json(_): Json<ActionRequest>) -> Response {
+    info!("Control: shutdown requested.");
+    control.shutdown();
+    accepted("shutting down after this turn")
+}
+
+/// Serve the control panel and API on `addr` until the process exits.
+pub async fn serve_control(addr: &str, control: Arc<Control>) -> Result<()> {
+    let app = Router::new()
+        .route("/", get(index))
+        .route("/api/status", get(status))
+        .route("/api/history", get(history))
+        .route("/api/query", post(query))
+        .route("/api/system_prompt", post(system_prompt))
+        .route("/api/pause", post(pause))
+        .route("/api/resume", post(resume))
+        .route("/api/voice", post(voice))
+        .route("/api/sd_model", post(sd_model))
+        .route("/api/shutdown", post(shutdown))
+        .with_state(control);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Control panel listening on http://{}/", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+const CONTROL_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>RsLLM Control</title>
+<style>
+body { font-family: sans-serif; margin: 2em; max-width: 60em; }
+textarea, input { width: 100%; box-sizing: border-box; }
+section { margin-bottom: 1.5em; }
+#history div { border-bottom: 1px solid #ccc; padding: 0.3em 0; white-space: pre-wrap; }
+</style>
+</head>
+<body>
+<h1>RsLLM Control</h1>
+<section><pre id="status"></pre></section>
+<section>
+<h3>Query</h3>
+<textarea id="query" rows="3"></textarea>
+<button onclick="post('query', {query: value('query')})">Send</button>
+</section>
+<section>
+<h3>System prompt</h3>
+<textarea id="prompt" rows="4"></textarea>
+<button onclick="post('system_prompt', {prompt: value('prompt')})">Change</button>
+</section>
+<section>
+<h3>Story</h3>
+<button onclick="post('pause')">Pause</button>
+<button onclick="post('resume')">Resume</button>
+<button onclick="if (confirm('Shut down the show?')) post('shutdown')">Shut down</button>
+</section>
+<section>
+<h3>Voice and image model</h3>
+<input id="voice" placeholder="en_US/vctk_low#p303">
+<button onclick="post('voice', {voice: value('voice')})">Set voice</button>
+<select id="sd_model"><option>1.5</option><option>2.1</option><option>xl</option><option selected>turbo</option><option>Custom</option></select>
+<button onclick="post('sd_model', {model: value('sd_model')})">Set model</button>
+</section>
+<section><h3>History</h3><div id="history"></div></section>
+<script>
+function value(id) { return document.getElementById(id).value; }
+async function post(path, body) {
+  const response = await fetch('/api/' + path, {method: 'POST', headers: {'Content-Type': 'application/json'}, body: JSON.stringify(body || {})});
+  const result = await response.json();
+  alert(result.status || result.error);
+  refresh();
+}
+async function refresh() {
+  const status = await (await fetch('/api/status')).json();
+  document.getElementById('status').textContent = JSON.stringify(status, null, 2);
+  const history = await (await fetch('/api/history')).json();
+  const list = document.getElementById('history');
+  list.replaceChildren(...history.map(m => { const d = document.createElement('div'); d.textContent = m.role + ': ' + m.content; return d; }));
+}
+refresh();
+setInterval(refresh, 5000);
+</script>
+</body>
+</html>
+"#;
@@ -0,0 +1,121 @@
+/*
+ * history_summary.rs
+ * ------------------
+ * Rolling memory for long running conversations, older turns are summarized
+ * by the LLM itself into a system message while recent turns stay verbatim.
+*/
+
+use crate::llm_backend::{GenerationParams, LlmBackend};
+use crate::openai_api::Message;
+use crate::token_budget::TokenCounter;
+use anyhow::Result;
+use log::info;
+use std::sync::Arc;
+
+/// Start of the memory system message, used to find and replace it.
+pub const MEMORY_PREFIX: &str = "Memory of the conversation so far: ";
+
+fn is_memory(message: &Message) -> bool {
+    message.role == "system" && message.content.starts_with(MEMORY_PREFIX)
+}
+
+/// Summarize all but the `keep_recent` newest non-system messages into the memory message.
+/// The transcript sent for summarizing is cut to `budget` tokens, oldest lines first.
+/// Returns how many messages were folded into the memory.
+pub async fn summarize_history(
+    backend: &Arc<dyn LlmBackend>,
+    messages: &mut Vec<Message>,
+    counter: &TokenCounter,
+    budget: usize,
+    keep_recent: usize,
+    summary_prompt: &str,
+    params: GenerationParams,
+) -> Result<usize> {
+    let memory = messages
+        .iter()
+        .find(|m| is_memory(m))
+        .map(|m| m.content[MEMORY_PREFIX.len()..].to_string())
+        .unwrap_or_default();
+
+    let conversation: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.role != "system")
+        .map(|(index, _)| index)
+        .collect();
+    if conversation.len() <= keep_recent {
+        return Ok(0);
+    }
+    let older = &conversation[..conversation.len() - keep_recent];
+
+    let mut lines: Vec<String> = older
+        .iter()
+        .map(|&index| format!("{}: {}", messages[index].role, messages[index].content))
+        .collect();
+    let overhead = counter.count(summary_prompt) + counter.count(&memory);
+    while lines.len() > 1 && overhead + counter.count(&lines.join("\n")) > budget {
+        lines.remove(0);
+    }
+
+    let request = vec![
+        Message {
+            role: "system".to_string(),
+            content: summary_prompt.to_string(),
+            ..Default::default()
+        },
+        Message {
+            role: "user".to_string(),
+            content: format!(
+                "Previous memory: {}\n\nConversation:\n{}",
+                if memory.is_empty() { "none" } else { &memory },
+                lines.join("\n")
+            ),
+            ..Default::default()
+        },
+        Message {
+            role: "assistant".to_string(),
+            content: String::new(),
+            ..Default::default()
+        },
+    ];
+
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<String>(32768);
+    let generation = backend.generate(request, params, sender);
+    let mut summary = String::new();
+    while let Some(received) = receiver.recv().await {
+        summary.push_str(&received);
+    }
+    generation.await??;
+    let summary = summary.trim();
+    if summary.is_empty() {
+        return Err(anyhow::anyhow!("the LLM returned an empty summary"));
+    }
+
+    // drop the summarized turns and the old memory, the new memory follows the system prompt
+    let summarized = older.len();
+    let mut index = 0;
+    messages.retain(|m| {
+        let keep = !is_memory(m) && !older.contains(&index);
+        index += 1;
+        keep
+    });
+    let position = messages
+        .iter()
+        .position(|m| m.role != "system")
+        .unwrap_or(messages.len());
+    messages.insert(
+        position,
+        Message {
+            role: "system".to_string(),
+            content: format!("{}{}", MEMORY_PREFIX, summary),
+            ..Default::default()
+        },
+    );
+    info!(
+        "Summarized {} messages into a {} token memory.",
+        summarized,
+        counter.count(summary)
+    );
+
+    Ok(summarized)
+}
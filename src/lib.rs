@@ -9,9 +9,18 @@
 
 pub mod args;
 pub mod audio;
+pub mod candle_llm;
 pub mod candle_metavoice;
 pub mod candle_mistral;
+pub mod capture_buffer;
+pub mod chat_template;
+pub mod config;
+pub mod control;
+pub mod history_summary;
+pub mod llm_backend;
 pub mod mimic3_tts;
+pub mod model_files;
+pub mod model_service;
 pub mod mpegts;
 #[cfg(feature = "ndi")]
 pub mod ndi;
@@ -19,11 +28,22 @@ pub mod network_capture;
 pub mod openai_api;
 pub mod openai_tts;
 pub mod pipeline;
+pub mod quantized_gemma;
+pub mod sampling;
 pub mod sd_automatic;
+pub mod server;
+pub mod schedule;
+pub mod session;
+pub mod sse;
 pub mod stable_diffusion;
+pub mod stats_history;
+pub mod stop_sequences;
 pub mod stream_data;
 pub mod system_stats;
+pub mod token_budget;
+pub mod tools;
 pub mod twitch_client;
+pub mod usage;
 use serde_json::{json, Value};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -214,6 +234,7 @@ pub fn truncate_tokens(text: &str, max_tokens: usize) -> String {
     }
 }
 
+// Word chunk estimate, token_budget::TokenCounter counts with the model tokenizer
 pub fn count_tokens(text: &str) -> usize {
     let mut token_count = 0;
     for token in text.split_whitespace() {
@@ -0,0 +1,462 @@
+/*
+ * llm_backend.rs
+ * --------------
+ * Pluggable LLM backends, each streams generated tokens for a conversation
+ * over an mpsc channel so the main loop and twitch chat can use any of them.
+*/
+
+use crate::args::Args;
+use crate::chat_template::ChatTemplate;
+use crate::model_files::ModelFiles;
+use crate::model_service::{CandleModelConfig, ModelService};
+use crate::openai_api::{stream_completion, ClientOptions, Message, OpenAIRequest, StreamOptions};
+use crate::stop_sequences::parse_stop_sequences;
+use crate::token_budget::{context_window, TokenCounter};
+use crate::tools::ToolRegistry;
+use crate::usage::{Usage, UsageTimer};
+use anyhow::Result;
+use log::{debug, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+/// Sampling parameters for a single generation request.
+/// The penalties only reach the API, top-k, min-p, the repeat penalty and seed only local models.
+#[derive(Clone, Debug)]
+pub struct GenerationParams {
+    pub max_tokens: usize,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub top_k: usize,
+    pub min_p: f32,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    pub seed: u64,
+    pub stop: Vec<String>,
+    pub presence_penalty: f32,
+    pub frequency_penalty: f32,
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        GenerationParams {
+            max_tokens: 800,
+            temperature: 0.8,
+            top_p: 1.0,
+            top_k: 0,
+            min_p: 0.0,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            seed: 0,
+            stop: Vec::new(),
+            presence_penalty: 0.0,
+            frequency_penalty: 0.0,
+        }
+    }
+}
+
+impl GenerationParams {
+    pub fn from_args(args: &Args, max_tokens: usize) -> Self {
+        GenerationParams {
+            max_tokens,
+            temperature: args.temperature,
+            top_p: args.top_p,
+            top_k: args.top_k,
+            min_p: args.min_p,
+            repeat_penalty: args.repeat_penalty,
+            repeat_last_n: args.repeat_last_n,
+            seed: args.seed,
+            stop: parse_stop_sequences(&args.stop),
+            presence_penalty: args.presence_penalty,
+            frequency_penalty: args.frequency_penalty,
+        }
+    }
+}
+
+pub trait LlmBackend: Send + Sync {
+    /// Backend name for logging.
+    fn name(&self) -> &str;
+
+    /// Start generating a reply to `messages`, tokens are streamed over `sender`
+    /// and the channel is closed when generation is finished, returning its usage.
+    fn generate(
+        &self,
+        messages: Vec<Message>,
+        params: GenerationParams,
+        sender: Sender<String>,
+    ) -> JoinHandle<Result<Usage>>;
+
+    /// Token counter for the backend model, the word chunk estimate when the tokenizer is unknown.
+    fn token_counter(&self) -> TokenCounter {
+        TokenCounter::default()
+    }
+
+    /// Context window in tokens, 0 when unknown.
+    fn context_window(&self) -> usize {
+        0
+    }
+
+    /// True when message images reach the model, text only backends ignore them.
+    fn supports_images(&self) -> bool {
+        false
+    }
+}
+
+/// OpenAI compatible chat completions API, llama.cpp server or api.openai.com
+pub struct OpenAiBackend {
+    pub llm_host: String,
+    pub llm_path: String,
+    pub model: String,
+    pub api_key: String,
+    pub stream: bool,
+    pub debug_inline: bool,
+    pub show_output_errors: bool,
+    /// Local tools offered to the LLM, empty to send no tools
+    pub tools: ToolRegistry,
+    /// Max tool call rounds before the answer is taken as is
+    pub max_tool_rounds: usize,
+    /// Timeouts and retries of the HTTP client
+    pub client_options: ClientOptions,
+    /// Send a prompt rendered with this template to a completions endpoint, None for chat
+    pub chat_template: Option<ChatTemplate>,
+    /// The model accepts images, set with --llm-vision
+    pub vision: bool,
+}
+
+impl LlmBackend for OpenAiBackend {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    // a raw prompt is text only
+    fn supports_images(&self) -> bool {
+        self.vision && self.chat_template.is_none()
+    }
+
+    fn generate(
+        &self,
+        messages: Vec<Message>,
+        params: GenerationParams,
+        sender: Sender<String>,
+    ) -> JoinHandle<Result<Usage>> {
+        let mut params = params;
+        // a raw prompt has no turns, so end the answer where the model starts another one
+        let prompt = match &self.chat_template {
+            Some(chat_template) => {
+                for stop in chat_template.stop_sequences() {
+                    if !params.stop.contains(&stop) {
+                        params.stop.push(stop);
+                    }
+                }
+                match chat_template.render(&messages) {
+                    Ok(prompt) => Some(prompt),
+                    Err(e) => return tokio::spawn(async move { Err(e) }),
+                }
+            }
+            None => None,
+        };
+        let llm_host = self.llm_host.clone();
+        let llm_path = self.llm_path.clone();
+        let model = self.model.clone();
+        let api_key = self.api_key.clone();
+        let stream = self.stream;
+        let debug_inline = self.debug_inline;
+        let show_output_errors = self.show_output_errors;
+        let tools = self.tools.clone();
+        // tools need chat messages
+        let max_tool_rounds = if prompt.is_some() {
+            0
+        } else {
+            self.max_tool_rounds
+        };
+        let client_options = self.client_options.clone();
+        tokio::spawn(async move {
+            let tool_definitions = tools.definitions();
+            let mut messages = messages;
+            let mut round = 0;
+            let mut timer = UsageTimer::start();
+            let counter = TokenCounter::default();
+            let (mut prompt_tokens, mut completion_tokens) = (0, 0);
+            let mut exact = true;
+            loop {
+                // the last round offers no tools so the LLM has to answer
+                let offer_tools = round < max_tool_rounds;
+                let open_ai_request = OpenAIRequest {
+                    model: &model,
+                    max_tokens: &params.max_tokens,
+                    messages: if prompt.is_some() {
+                        Vec::new()
+                    } else {
+                        messages.clone()
+                    },
+                    prompt: prompt.clone(),
+                    temperature: &params.temperature,
+                    top_p: &params.top_p,
+                    presence_penalty: &params.presence_penalty,
+                    frequency_penalty: &params.frequency_penalty,
+                    stop: &params.stop,
+                    tools: if offer_tools {
+                        &tool_definitions[..]
+                    } else {
+                        &[]
+                    },
+                    stream: &stream,
+                    stream_options: if stream {
+                        Some(StreamOptions {
+                            include_usage: true,
+                        })
+                    } else {
+                        None
+                    },
+                };
+
+                let round_start = timer.elapsed();
+                let completion = stream_completion(
+                    open_ai_request,
+                    &api_key,
+                    &llm_host,
+                    &llm_path,
+                    &client_options,
+                    debug_inline,
+                    show_output_errors,
+                    sender.clone(),
+                )
+                .await?;
+                if let Some(time_to_first_token) = completion.time_to_first_token {
+                    timer.first_token_at(round_start + time_to_first_token);
+                }
+                // servers without usage reporting get the word chunk estimate
+                match &completion.usage {
+                    Some(usage) => {
+                        prompt_tokens += usage.prompt_tokens;
+                        completion_tokens += usage.completion_tokens;
+                    }
+                    None => {
+                        exact = false;
+                        prompt_tokens += match &prompt {
+                            Some(prompt) => counter.count(prompt),
+                            None => counter.count_messages(&messages),
+                        };
+                        completion_tokens += completion.content_chunks;
+                    }
+                }
+                if completion.finish_reason.as_deref() == Some("length") {
+                    warn!("LLM answer was cut off at {} tokens.", params.max_tokens);
+                }
+                let tool_calls = completion.tool_calls;
+                if tool_calls.is_empty() || !offer_tools {
+                    break;
+                }
+
+                // answer each call with a tool message and ask again
+                round += 1;
+                info!(
+                    "LLM requested {} tool calls, round {}",
+                    tool_calls.len(),
+                    round
+                );
+                let results: Vec<Message> = tool_calls
+                    .iter()
+                    .map(|tool_call| Message {
+                        role: "tool".to_string(),
+                        content: tools.call(tool_call),
+                        tool_call_id: Some(tool_call.id.clone()),
+                        ..Default::default()
+                    })
+                    .collect();
+                messages.push(Message {
+                    role: "assistant".to_string(),
+                    tool_calls: Some(tool_calls),
+                    ..Default::default()
+                });
+                messages.extend(results);
+            }
+            let usage = timer.finish("openai", &model, prompt_tokens, completion_tokens, exact);
+            usage.log();
+            Ok(usage)
+        })
+    }
+}
+
+/// Local candle model kept loaded by a shared model service
+pub struct CandleBackend {
+    pub service: Arc<ModelService>,
+    pub chat_template: ChatTemplate,
+}
+
+impl LlmBackend for CandleBackend {
+    fn name(&self) -> &str {
+        &self.service.config().candle_llm
+    }
+
+    fn token_counter(&self) -> TokenCounter {
+        TokenCounter::new(self.service.tokenizer())
+    }
+
+    fn context_window(&self) -> usize {
+        context_window(&self.service.config().candle_llm)
+    }
+
+    fn generate(
+        &self,
+        messages: Vec<Message>,
+        mut params: GenerationParams,
+        sender: Sender<String>,
+    ) -> JoinHandle<Result<Usage>> {
+        // the prompt is raw text, so end the answer where the model starts another turn
+        for stop in self.chat_template.stop_sequences() {
+            if !params.stop.contains(&stop) {
+                params.stop.push(stop);
+            }
+        }
+        let prompt = match self.chat_template.render(&messages) {
+            Ok(prompt) => prompt,
+            Err(e) => return tokio::spawn(async move { Err(e) }),
+        };
+        debug!("\nPrompt: {}", prompt);
+        let service = self.service.clone();
+        let counter = self.token_counter();
+        let backend = self.name().to_string();
+        let model = service.config().model_id.clone();
+        tokio::spawn(async move {
+            // relay the tokens to time the first one and count the answer
+            let mut timer = UsageTimer::start();
+            let (model_sender, mut model_receiver) = tokio::sync::mpsc::channel::<String>(32768);
+            let generation = tokio::spawn({
+                let service = service.clone();
+                let prompt = prompt.clone();
+                async move { service.generate(prompt, params, model_sender).await }
+            });
+            let mut answer = String::new();
+            while let Some(token) = model_receiver.recv().await {
+                timer.first_token();
+                answer.push_str(&token);
+                if sender.send(token).await.is_err() {
+                    break;
+                }
+            }
+            generation.await??;
+            let usage = timer.finish(
+                &backend,
+                &model,
+                counter.count(&prompt),
+                counter.count(&answer),
+                counter.is_exact(),
+            );
+            usage.log();
+            Ok(usage)
+        })
+    }
+}
+
+/// Create a local candle backend by model name, mistral, gemma, llama, phi or qwen.
+/// The model is loaded once and shared by every backend using the same model,
+/// the chat format is a preset name or template file, see `ChatTemplate::load`.
+pub fn candle_backend(
+    candle_llm: &str,
+    model_id: &str,
+    quantized: bool,
+    model_files: ModelFiles,
+    chat_format: &str,
+    queue_size: usize,
+) -> Result<Arc<dyn LlmBackend>> {
+    match candle_llm {
+        "mistral" | "gemma" | "llama" | "phi" | "qwen" => {}
+        _ => {
+            return Err(anyhow::anyhow!(
+                "The specified LLM {} is not supported",
+                candle_llm
+            ))
+        }
+    }
+    let chat_template = ChatTemplate::load(chat_format)?;
+    let config = CandleModelConfig {
+        candle_llm: candle_llm.to_string(),
+        model_id: model_id.to_string(),
+        quantized,
+        model_files,
+    };
+    Ok(Arc::new(CandleBackend {
+        service: ModelService::shared(config, queue_size),
+        chat_template,
+    }))
+}
+
+/// Select the main loop backend from the command line configuration.
+pub fn backend_from_args(
+    args: &Args,
+    llm_host: &str,
+    openai_key: &str,
+) -> Result<Arc<dyn LlmBackend>> {
+    let model_files = ModelFiles::new(
+        &args.model_dir,
+        &args.model_tokenizer,
+        &args.model_config,
+        &args.model_weights,
+    );
+    let chat_format = match args.chat_format.as_str() {
+        // the chat_template shipped with the local model files
+        "model" => model_files
+            .file(&None, "tokenizer_config.json")
+            .display()
+            .to_string(),
+        chat_format => chat_format.to_string(),
+    };
+    if args.use_api || args.use_openai {
+        let (llm_path, chat_template) = if args.api_completions {
+            let llm_path = if args.llm_path == "/v1/chat/completions" {
+                "/v1/completions".to_string()
+            } else {
+                args.llm_path.clone()
+            };
+            // "" is the plain preset, the candle default format is for the local models
+            (llm_path, Some(ChatTemplate::load(&chat_format)?))
+        } else {
+            (args.llm_path.clone(), None)
+        };
+        Ok(Arc::new(OpenAiBackend {
+            llm_host: llm_host.to_string(),
+            llm_path,
+            model: args.model.clone(),
+            api_key: openai_key.to_string(),
+            stream: !args.no_stream,
+            debug_inline: args.debug_inline,
+            show_output_errors: args.show_output_errors,
+            tools: ToolRegistry::from_names(&args.tools)?,
+            max_tool_rounds: args.max_tool_rounds,
+            client_options: ClientOptions {
+                connect_timeout: Duration::from_secs(args.llm_connect_timeout),
+                read_timeout: Duration::from_secs(args.llm_read_timeout),
+                max_retries: args.llm_max_retries,
+                ..Default::default()
+            },
+            chat_template,
+            vision: args.llm_vision,
+        }))
+    } else {
+        candle_backend(
+            &args.candle_llm,
+            &args.model_id,
+            args.quantized,
+            model_files,
+            match chat_format.as_str() {
+                "" => default_chat_format(&args.candle_llm),
+                chat_format => chat_format,
+            },
+            args.model_queue_size,
+        )
+    }
+}
+
+/// Chat format matching the prompt tokens each local model was trained on.
+pub fn default_chat_format(candle_llm: &str) -> &'static str {
+    match candle_llm {
+        "gemma" => "google",
+        "mistral" => "llama2",
+        "llama" => "llama3",
+        "phi" => "phi3",
+        "qwen" => "chatml",
+        _ => "",
+    }
+}
@@ -1,4 +1,3 @@
-
 /*
  * RsLLM OpenAI API client
  * This program is a simple client for the OpenAI API. It sends a prompt to the API and prints the
@@ -18,27 +17,36 @@
 
 use clap::Parser;
 use ctrlc;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rsllm::args::Args;
-use rsllm::candle_gemma::gemma;
-use rsllm::candle_mistral::mistral;
+use rsllm::capture_buffer::{CaptureBuffer, CaptureFormat};
 use rsllm::clean_tts_input;
+use rsllm::config::{apply_hot_reload, ConfigFile};
+use rsllm::control::{serve_control, Control, ControlStatus};
 use rsllm::count_tokens;
 use rsllm::handle_long_string;
+use rsllm::history_summary::summarize_history;
+use rsllm::llm_backend::{backend_from_args, GenerationParams};
 use rsllm::network_capture::{network_capture, NetworkCapture};
-use rsllm::openai_api::{format_messages_for_llm, stream_completion, Message, OpenAIRequest};
+use rsllm::openai_api::{ImageUrl, Message};
 #[cfg(feature = "ndi")]
 use rsllm::pipeline::send_to_ndi;
 use rsllm::pipeline::{process_image, process_speech, MessageData, ProcessedData};
-use rsllm::stable_diffusion::{SDConfig, StableDiffusionVersion};
+use rsllm::schedule::Schedule;
+use rsllm::server::{serve, ServerState};
+use rsllm::session::Session;
+use rsllm::stable_diffusion::SDConfig;
+use rsllm::stats_history::StatsHistory;
 use rsllm::stream_data::{
     get_pid_map, identify_video_pid, is_mpegts_or_smpte2110, parse_and_store_pat, process_packet,
     update_pid_map, Codec, PmtInfo, StreamData, Tr101290Errors, PAT_PID,
 };
 use rsllm::stream_data::{process_mpegts_packet, process_smpte2110_packet};
+use rsllm::system_stats::{get_system_stats, set_watched_processes};
+use rsllm::token_budget::trim_messages;
 use rsllm::twitch_client::daemon as twitch_daemon;
+use rsllm::usage::SessionUsage;
 use rsllm::{current_unix_timestamp_ms, hexdump, hexdump_ascii};
-use rsllm::{get_stats_as_json, StatsType};
 use serde_json::{self, json};
 use std::collections::HashMap;
 use std::env;
@@ -53,6 +61,31 @@ use tokio::sync::{Mutex, Semaphore};
 use tokio::time::Duration;
 use uuid::Uuid;
 
+// Put the current system prompt first in the conversation
+fn replace_system_message(messages: &mut Vec<Message>, system_message: &Message) {
+    match messages.first_mut() {
+        Some(first) if first.role == "system" => *first = system_message.clone(),
+        _ => messages.insert(0, system_message.clone()),
+    }
+}
+
+// The greeting and assistant image sent to the pipeline when the show or a segment starts
+fn greeting_message(args: &Args, paragraph_count: usize) -> MessageData {
+    let sd_config = SDConfig::from_args(args, args.assistant_image_prompt.clone());
+    let output_id = Uuid::new_v4().simple().to_string(); // Generates a UUID and converts it to a simple, hyphen-free string
+    MessageData {
+        paragraph: args.greeting.to_string(),
+        output_id: output_id.to_string(),
+        paragraph_count,
+        sd_config,
+        mimic3_voice: args.mimic3_voice.to_string(),
+        subtitle_position: args.subtitle_position.to_string(),
+        args: args.clone(),
+        shutdown: false,
+        last_message: false,
+    }
+}
+
 #[tokio::main]
 async fn main() {
     // Read .env file
@@ -62,7 +95,44 @@ async fn main() {
     let _ = env_logger::try_init();
 
     // Parse command line arguments
-    let args = Args::parse();
+    let mut args = Args::parse();
+
+    // Settings from a config file profile, command line flags and the environment still win
+    let mut config_file = if args.config.is_empty() {
+        None
+    } else {
+        let mut config_file = ConfigFile::new(&args.config, &args.profile);
+        match config_file.load() {
+            Ok(loaded) => args = loaded,
+            Err(e) => {
+                eprintln!("Invalid config {}: {}", args.config, e);
+                std::process::exit(1);
+            }
+        }
+        Some(config_file)
+    };
+    args.convert_deprecated();
+
+    // Segments of the show switched by the clock or after their stories
+    let mut schedule = if args.schedule.is_empty() {
+        None
+    } else {
+        match Schedule::load(&args.schedule) {
+            Ok(mut schedule) => {
+                if let Some(segment) = schedule.next_segment(chrono::Local::now().naive_local()) {
+                    info!("Schedule: starting with segment {}.", segment.name);
+                    segment.apply(&mut args);
+                }
+                Some(schedule)
+            }
+            Err(e) => {
+                eprintln!("Invalid schedule {}: {}", args.schedule, e);
+                std::process::exit(1);
+            }
+        }
+    };
+    // greeting of a segment that started while the show was paused, given on resume
+    let mut segment_greeting = false;
 
     // Create an atomic bool to track if Ctrl+C is pressed
     let running_ctrlc = Arc::new(AtomicBool::new(true));
@@ -101,9 +171,20 @@ async fn main() {
         }
     }
 
-    let system_message = Message {
+    // Companion services to include in the system stats
+    if args.ai_os_stats {
+        set_watched_processes(
+            args.ai_os_stats_processes
+                .split(',')
+                .map(|s| s.to_string())
+                .collect(),
+        );
+    }
+
+    let mut system_message = Message {
         role: "system".to_string(),
         content: args.system_prompt.to_string(),
+        ..Default::default()
     };
 
     let processed_data_store: Arc<Mutex<HashMap<usize, ProcessedData>>> =
@@ -120,19 +201,24 @@ async fn main() {
     let pipeline_sem = Arc::new(Semaphore::new(args.pipeline_concurrency));
     // Pipeline processing task for image and speech together as a single task
     // Pipeline processing task for image and speech together as a single task
+    // create a black frame image in the vec[] to use initially as last_images
+    // Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>, also the frame shown to a vision LLM
+    let black_frame = image::ImageBuffer::from_fn(1920, 1080, |_, _| image::Rgb([0, 0, 0]));
+    let last_images = Arc::new(Mutex::new(vec![black_frame.clone()]));
+    // set once Stable Diffusion made a frame, a vision LLM is not shown the black placeholder
+    let sd_frame_ready = Arc::new(AtomicBool::new(false));
     let pipeline_processing_task = {
         let pipeline_sem = Arc::clone(&pipeline_sem);
         let processed_data_store = processed_data_store.clone();
-        // create a black frame image in the vec[] to use initially as last_images
-        // Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>
-        let black_frame = image::ImageBuffer::from_fn(1920, 1080, |_, _| image::Rgb([0, 0, 0]));
-        let last_images = Arc::new(Mutex::new(vec![black_frame.clone()]));
+        let last_images = Arc::clone(&last_images);
+        let sd_frame_ready = Arc::clone(&sd_frame_ready);
         tokio::spawn(async move {
             while let Some(message_data) = pipeline_task_receiver.recv().await {
                 let processed_data_store = processed_data_store.clone();
                 let message_data_clone = message_data.clone();
                 let pipeline_sem = Arc::clone(&pipeline_sem);
                 let last_images_clone = Arc::clone(&last_images);
+                let sd_frame_ready = Arc::clone(&sd_frame_ready);
                 // channels to pass images back for the last_images vec
                 let (image_tx, mut image_rx) =
                     mpsc::channel::<Vec<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>>(100);
@@ -189,6 +275,7 @@ async fn main() {
                         // If the processed images are not empty, update the last_images
                         let mut last_images_guard = last_images_clone.lock().await;
                         *last_images_guard = images.clone();
+                        sd_frame_ready.store(true, Ordering::SeqCst);
                     }
 
                     // send images to the image channel
@@ -399,6 +486,14 @@ async fn main() {
     let running_processor_network = Arc::new(AtomicBool::new(true));
     let running_processor_network_clone = running_processor_network.clone();
 
+    let capture_format = match args.capture_buffer_format.parse::<CaptureFormat>() {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("Invalid --capture-buffer-format: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     let processing_handle = tokio::spawn(async move {
         let mut decode_batch = Vec::new();
         let mut video_pid: Option<u16> = Some(0xFFFF);
@@ -409,6 +504,19 @@ async fn main() {
             packet: Vec::new(),
         };
 
+        // Rolling buffer of raw packets dumped to a file as evidence when errors are detected
+        let mut capture_buffer = if args.capture_buffer_seconds > 0 {
+            Some(CaptureBuffer::new(
+                args.capture_buffer_seconds,
+                args.payload_offset,
+                capture_format,
+                args.capture_buffer_dir.clone(),
+            ))
+        } else {
+            None
+        };
+        let mut capture_files: Vec<String> = Vec::new();
+
         let mut packet_last_sent_ts = Instant::now();
         let mut count = 0;
         while running_processor_network_clone.load(Ordering::SeqCst) {
@@ -422,6 +530,10 @@ async fn main() {
                         packet.len()
                     );
 
+                    if let Some(capture_buffer) = capture_buffer.as_mut() {
+                        capture_buffer.push(Arc::clone(&packet));
+                    }
+
                     // Check if chunk is MPEG-TS or SMPTE 2110
                     let chunk_type = is_mpegts_or_smpte2110(&packet[args.payload_offset..]);
                     if chunk_type != 1 {
@@ -524,6 +636,7 @@ async fn main() {
                         }
 
                         // Check for TR 101 290 errors
+                        let errors_before = tr101290_errors.total();
                         process_packet(
                             &mut stream_data,
                             &mut tr101290_errors,
@@ -532,6 +645,33 @@ async fn main() {
                         );
                         count += 1;
 
+                        // Dump the capture buffer if any of the error counters jumped
+                        if tr101290_errors.total() > errors_before {
+                            if let Some(capture_buffer) = capture_buffer.as_mut() {
+                                let reason = format!("pid{}_errors", stream_data.pid);
+                                match capture_buffer.dump(&reason) {
+                                    Ok(Some(path)) => {
+                                        error!(
+                                            "STATUS::CAPTURE:DUMP: PID {} errors [{}] evidence saved to {}",
+                                            stream_data.pid,
+                                            tr101290_errors,
+                                            path.display()
+                                        );
+                                        capture_files.push(format!(
+                                            "PID {} errors detected at {}, capture saved to {}",
+                                            stream_data.pid,
+                                            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                                            path.display()
+                                        ));
+                                    }
+                                    Ok(None) => {}
+                                    Err(e) => {
+                                        error!("Failed to write capture buffer: {}", e);
+                                    }
+                                }
+                            }
+                        }
+
                         decode_batch.push(stream_data);
                     }
 
@@ -580,6 +720,15 @@ async fn main() {
                         let pid_map = format!("{}: {}", pretty_date_time, get_pid_map());
                         network_packet_dump.push_str(&pid_map);
 
+                        // add any error capture files written since the last batch
+                        if !capture_files.is_empty() {
+                            network_packet_dump.push_str("\nError Captures:\n");
+                            for capture_file in capture_files.drain(..) {
+                                network_packet_dump.push_str(&capture_file);
+                                network_packet_dump.push_str("\n");
+                            }
+                        }
+
                         // Send the network packet dump to the Main thread
                         if let Err(e) = batch_tx.send(network_packet_dump.clone()).await {
                             eprintln!("Failed to send decode batch: {}", e);
@@ -662,7 +811,7 @@ async fn main() {
         });
     }
     let poll_interval = args.poll_interval;
-    let poll_interval_duration = Duration::from_millis(poll_interval);
+    let mut poll_interval_duration = Duration::from_millis(poll_interval);
     let mut poll_start_time = Instant::now();
     let mut poll_end_time = Instant::now();
     if args.daemon {
@@ -675,50 +824,47 @@ async fn main() {
     }
     let mut iterations = 0;
 
+    // Restore the conversation and counters of a named session
+    let mut session = if args.session.is_empty() {
+        None
+    } else {
+        match Session::load(&args.session_dir, &args.session) {
+            Ok(Some(saved)) => {
+                info!(
+                    "Restored session {} with {} messages at iteration {}.",
+                    saved.name,
+                    saved.messages.len(),
+                    saved.iterations
+                );
+                messages = saved.messages.clone();
+                // the current system prompt replaces the saved one
+                replace_system_message(&mut messages, &system_message);
+                iterations = saved.iterations;
+                total_paragraph_count = saved.paragraph_count;
+                Some(saved)
+            }
+            Ok(None) => {
+                info!("Starting new session {}.", args.session);
+                Some(Session::new(&args.session))
+            }
+            Err(e) => {
+                error!("Failed to load session {}: {}", args.session, e);
+                std::process::exit(1);
+            }
+        }
+    };
+    // LLM usage of this run, continued from the saved session
+    let mut session_usage: SessionUsage = session
+        .as_ref()
+        .map(|s| s.usage.clone())
+        .unwrap_or_default();
+
+    // Rolling history of system stats for trend analysis in ai_os_stats mode
+    let mut stats_history = StatsHistory::new(args.ai_os_stats_history);
+
     // Boot up message and image repeat of the query sent to the pipeline
     if args.sd_image || args.tts_enable || args.oai_tts || args.mimic3_tts {
-        let mut sd_config = SDConfig::new();
-        sd_config.prompt = args.assistant_image_prompt.clone();
-        sd_config.height = Some(args.sd_height);
-        sd_config.width = Some(args.sd_width);
-        sd_config.image_position = Some(args.image_alignment.clone());
-        sd_config.intermediary_images = args.sd_intermediary_images;
-        sd_config.custom_model = Some(args.sd_custom_model.clone());
-        // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-        sd_config.sd_version = if args.sd_model == "1.5" {
-            StableDiffusionVersion::V1_5
-        } else if args.sd_model == "2.1" {
-            StableDiffusionVersion::V2_1
-        } else if args.sd_model == "xl" {
-            StableDiffusionVersion::Xl
-        } else if args.sd_model == "turbo" {
-            StableDiffusionVersion::Turbo
-        } else if args.sd_model == "Custom" {
-            StableDiffusionVersion::Custom
-        } else {
-            StableDiffusionVersion::V1_5
-        };
-
-        let output_id = Uuid::new_v4().simple().to_string(); // Generates a UUID and converts it to a simple, hyphen-free string
-        if args.sd_scaled_height > 0 {
-            sd_config.scaled_height = Some(args.sd_scaled_height);
-        }
-        if args.sd_scaled_width > 0 {
-            sd_config.scaled_width = Some(args.sd_scaled_width);
-        }
-        sd_config.n_steps = args.sd_n_steps;
-        // just send a message with the last_message field true to indicate the end of the response
-        let message_data_for_pipeline = MessageData {
-            paragraph: args.greeting.to_string(),
-            output_id: output_id.to_string(),
-            paragraph_count: total_paragraph_count,
-            sd_config,
-            mimic3_voice: args.mimic3_voice.to_string(),
-            subtitle_position: args.subtitle_position.to_string(),
-            args: args.clone(),
-            shutdown: false,
-            last_message: false,
-        };
+        let message_data_for_pipeline = greeting_message(&args, total_paragraph_count);
 
         // For pipeline task
         pipeline_task_sender
@@ -729,20 +875,102 @@ async fn main() {
         total_paragraph_count += 1;
     }
 
-    loop {
-        let mut twitch_query = false;
-        let mut query = args.query.clone();
+    let openai_key = env::var("OPENAI_API_KEY")
+        .ok()
+        .unwrap_or_else(|| "NO_API_KEY".to_string());
 
-        let openai_key = env::var("OPENAI_API_KEY")
-            .ok()
-            .unwrap_or_else(|| "NO_API_KEY".to_string());
+    if (args.use_openai || args.oai_tts) && openai_key == "NO_API_KEY" {
+        error!("OpenAI API key is not set. Please set the OPENAI_API_KEY environment variable.");
+        std::process::exit(1);
+    }
 
-        if (args.use_openai || args.oai_tts) && openai_key == "NO_API_KEY" {
-            error!(
-                "OpenAI API key is not set. Please set the OPENAI_API_KEY environment variable."
-            );
+    // LLM backend selected from the command line, api or local candle model
+    let llm_backend = match backend_from_args(&args, &llm_host, &openai_key) {
+        Ok(backend) => backend,
+        Err(e) => {
+            error!("{}. Exiting...", e);
             std::process::exit(1);
         }
+    };
+    info!("Using LLM backend {}", llm_backend.name());
+    // steer the show over HTTP, shutdown clears the same running flag as Ctrl+C
+    let control = Arc::new(Control::new(running_ctrlc.clone(), args.oai_tts));
+    if !args.control_addr.is_empty() {
+        let control_addr = args.control_addr.clone();
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_control(&control_addr, control).await {
+                error!("Control panel on {} stopped: {}", control_addr, e);
+            }
+        });
+    }
+
+    // share the loaded models with other tools on the network
+    if !args.server_addr.is_empty() {
+        let server_addr = args.server_addr.clone();
+        let server_state = ServerState::new(args.clone(), llm_backend.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve(&server_addr, server_state).await {
+                error!("Server on {} stopped: {}", server_addr, e);
+            }
+        });
+    }
+    if !args.llm_image.is_empty() && !llm_backend.supports_images() {
+        warn!(
+            "LLM backend {} is text only, not sending images, use --llm-vision for a vision model.",
+            llm_backend.name()
+        );
+    }
+
+    loop {
+        // settings changed in the config file take effect with this turn
+        if let Some(config_file) = config_file.as_mut() {
+            match config_file.reload_if_changed() {
+                Ok(Some((previous, reloaded))) => {
+                    let changed = apply_hot_reload(&mut args, &previous, &reloaded);
+                    info!("Config reloaded, changed {:?}.", changed);
+                    if changed.contains(&"system_prompt") {
+                        system_message.content = args.system_prompt.clone();
+                        replace_system_message(&mut messages, &system_message);
+                    }
+                    if changed.contains(&"poll_interval") {
+                        poll_interval_duration = Duration::from_millis(args.poll_interval);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => error!("Config reload failed, keeping the current settings: {}", e),
+            }
+        }
+
+        // the schedule switches to the next segment of the show with this turn
+        if let Some(schedule) = schedule.as_mut() {
+            if let Some(segment) = schedule.next_segment(chrono::Local::now().naive_local()) {
+                info!("Schedule: starting segment {}.", segment.name);
+                segment.apply(&mut args);
+                system_message.content = args.system_prompt.clone();
+                if segment.clear_history {
+                    messages.clear();
+                    messages.push(system_message.clone());
+                } else {
+                    replace_system_message(&mut messages, &system_message);
+                }
+                segment_greeting = segment.greeting.is_some();
+            }
+        }
+        // introduce the segment with its greeting, not over a paused show
+        if segment_greeting && !control.is_paused() {
+            segment_greeting = false;
+            if args.sd_image || args.tts_enable || args.oai_tts || args.mimic3_tts {
+                pipeline_task_sender
+                    .send(greeting_message(&args, total_paragraph_count))
+                    .await
+                    .expect("Failed to send segment greeting pipeline task");
+                total_paragraph_count += 1;
+            }
+        }
+
+        let mut twitch_query = false;
+        let mut query = args.query.clone();
 
         // clear messages from previous iteration if no_history is set to true
         if args.no_history {
@@ -767,6 +995,7 @@ async fn main() {
                             let twitch_message = Message {
                                 role: "user".to_string(),
                                 content: msg.to_string(),
+                                ..Default::default()
                             };
                             // store in history for context of chat room
                             messages.push(twitch_message);
@@ -787,6 +1016,44 @@ async fn main() {
             }
         }
 
+        // changes from the control panel take effect with this turn
+        if let Some(prompt) = control.take_system_prompt() {
+            system_message.content = prompt;
+            replace_system_message(&mut messages, &system_message);
+        }
+        if let Some(voice) = control.take_voice() {
+            args.mimic3_voice = voice;
+        }
+        if let Some(sd_model) = control.take_sd_model() {
+            args.sd_model = sd_model;
+        }
+        // a twitch !message goes first, injected queries wait in the queue for the next turn
+        let control_query = if twitch_query {
+            false
+        } else if let Some(injected) = control.take_query() {
+            query = injected;
+            true
+        } else {
+            false
+        };
+        control.publish(ControlStatus {
+            backend: llm_backend.name().to_string(),
+            iterations,
+            system_prompt: system_message.content.clone(),
+            voice: args.mimic3_voice.clone(),
+            sd_model: args.sd_model.clone(),
+            segment: schedule
+                .as_ref()
+                .map(|schedule| schedule.current_name().to_string())
+                .unwrap_or_default(),
+            pipeline_queue: pipeline_task_sender.max_capacity() - pipeline_task_sender.capacity(),
+            ..Default::default()
+        });
+        // the conversation is only copied while the control panel polls for it
+        if control.history_wanted() {
+            control.publish_history(messages.clone());
+        }
+
         // break the loop if we are not running as a daemon or hit max iterations
         let rctrlc_clone = running_ctrlc.clone();
         if (!rctrlc_clone.load(Ordering::SeqCst)
@@ -815,34 +1082,7 @@ async fn main() {
 
             // set a flag to stop the pipeline processing task with the message shutdown field
             let output_id = Uuid::new_v4().simple().to_string(); // Generates a UUID and converts it to a simple, hyphen-free string
-            let mut sd_config = SDConfig::new();
-            sd_config.prompt = args.assistant_image_prompt.to_string();
-            sd_config.height = Some(args.sd_height);
-            sd_config.width = Some(args.sd_width);
-            sd_config.image_position = Some(args.image_alignment.clone());
-            sd_config.intermediary_images = args.sd_intermediary_images;
-            sd_config.custom_model = Some(args.sd_custom_model.clone());
-            if args.sd_scaled_height > 0 {
-                sd_config.scaled_height = Some(args.sd_scaled_height);
-            }
-            if args.sd_scaled_width > 0 {
-                sd_config.scaled_width = Some(args.sd_scaled_width);
-            }
-            // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-            sd_config.sd_version = if args.sd_model == "1.5" {
-                StableDiffusionVersion::V1_5
-            } else if args.sd_model == "2.1" {
-                StableDiffusionVersion::V2_1
-            } else if args.sd_model == "xl" {
-                StableDiffusionVersion::Xl
-            } else if args.sd_model == "turbo" {
-                StableDiffusionVersion::Turbo
-            } else if args.sd_model == "Custom" {
-                StableDiffusionVersion::Custom
-            } else {
-                StableDiffusionVersion::V1_5
-            };
-            sd_config.n_steps = args.sd_n_steps;
+            let sd_config = SDConfig::from_args(&args, args.assistant_image_prompt.clone());
             pipeline_task_sender
                 .send(MessageData {
                     paragraph: "Alice is Shutting Down the AI Channel, goodbye!".to_string(),
@@ -882,7 +1122,13 @@ async fn main() {
         let mut max_tokens = args.max_tokens as usize;
 
         // Did not get a message from twitch, so don't process the query
-        if !twitch_query && args.twitch_client {
+        // paused from the control panel, injected queries and twitch chat still run
+        if control.is_paused() && !control_query && !twitch_query {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            continue;
+        }
+
+        if !twitch_query && !control_query && args.twitch_client {
             if args.continuous {
                 // only play a story after poll_interval_duration has passed, else continue
                 let elapsed_end = poll_end_time.elapsed();
@@ -905,6 +1151,7 @@ async fn main() {
             && iterations > 0
             && !args.interactive
             && (args.daemon || args.max_iterations > 1)
+            && !control_query
             && elapsed < poll_interval_duration
         {
             // Sleep only if the elapsed time is less than the poll interval
@@ -921,20 +1168,25 @@ async fn main() {
         poll_start_time = Instant::now();
 
         // OS and Network stats message
-        let system_stats_json = if args.ai_os_stats {
-            get_stats_as_json(StatsType::System).await
+        let system_stats = if args.ai_os_stats {
+            Some(get_system_stats())
         } else {
+            None
+        };
+        let system_stats_json = match system_stats.as_ref() {
+            Some(system_stats) => json!(system_stats),
             // Default input message
-            json!({})
+            None => json!({}),
         };
 
         // Add the system stats to the messages
         if !args.ai_os_stats && !args.ai_network_stats {
-            if !args.interactive && !query.is_empty() {
+            if (!args.interactive || control_query) && !query.is_empty() {
                 let query_clone = query.clone();
                 let user_message = Message {
                     role: "user".to_string(),
                     content: query_clone.to_string(),
+                    ..Default::default()
                 };
                 messages.push(user_message.clone());
             } else {
@@ -954,6 +1206,7 @@ async fn main() {
                 let user_message = Message {
                     role: "user".to_string(),
                     content: prompt.to_string(),
+                    ..Default::default()
                 };
                 messages.push(user_message.clone());
             }
@@ -980,6 +1233,7 @@ async fn main() {
                         decode_batch,
                         query
                     ),
+                    ..Default::default()
                 };
                 messages.push(network_stats_message.clone());
                 if msg_count >= 1 {
@@ -992,14 +1246,35 @@ async fn main() {
                 iterations,
                 chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f")
             );
+            // Add the trends over the stats history so spikes can be told apart from leaks
+            let mut system_stats_trends = String::new();
+            if args.ai_os_stats_history > 0 {
+                if let Some(system_stats) = system_stats.as_ref() {
+                    stats_history.push(system_stats);
+                    let trends = stats_history.trends();
+                    system_stats_trends = format!(
+                        "\nTrends over {} samples / {} seconds: {}\nTrend Summary: {}",
+                        trends.samples,
+                        trends.window_seconds,
+                        json!(trends.metrics).to_string(),
+                        if trends.summary.is_empty() {
+                            "stable, no significant trends".to_string()
+                        } else {
+                            trends.summary.join("; ")
+                        }
+                    );
+                }
+            }
             let system_stats_message = Message {
                 role: "user".to_string(),
                 content: format!(
-                    "{} System Stats: {}\nInstructions: {}",
+                    "{} System Stats: {}{}\nInstructions: {}",
                     pretty_date_time,
                     system_stats_json.to_string(),
+                    system_stats_trends,
                     query
                 ),
+                ..Default::default()
             };
             messages.push(system_stats_message.clone());
         }
@@ -1018,79 +1293,84 @@ async fn main() {
             println!("============= NEW RESPONSE ============");
         }
 
-        // measure size of messages in bytes and print it out
-        let messages_size = bincode::serialize(&messages).unwrap().len();
-        info!("Initial Messages size: {}", messages_size);
-
-        let llm_history_size_bytes: usize = args.llm_history_size; // max history size in bytes
-
-        // Separate system messages to preserve them
-        let (system_messages, mut non_system_messages): (Vec<_>, Vec<_>) =
-            messages.into_iter().partition(|m| m.role == "system");
-
-        let total_non_system_size: usize =
-            non_system_messages.iter().map(|m| m.content.len()).sum();
-
-        // If non-system messages alone exceed the limit, we need to trim
-        if !args.no_history
-            && args.daemon
-            && llm_history_size_bytes > 0
-            && total_non_system_size > llm_history_size_bytes
-        {
-            let mut excess_size = total_non_system_size - llm_history_size_bytes;
+        // count the prompt with the model tokenizer once it is loaded
+        let token_counter = llm_backend.token_counter();
+        let messages_tokens = token_counter.count_messages(&messages);
+        info!(
+            "Initial Messages tokens: {}{}",
+            messages_tokens,
+            if token_counter.is_exact() {
+                ""
+            } else {
+                " (estimated)"
+            }
+        );
 
-            info!(
-                "Pruning excess history size: removing {} of {} bytes to {} bytes.",
-                excess_size, total_non_system_size, llm_history_size_bytes
+        // the prompt budget leaves room in the context window for the reply
+        let context_size = if args.context_size > 0 {
+            args.context_size
+        } else {
+            llm_backend.context_window()
+        };
+        let mut token_budget = if context_size > 0 {
+            context_size.saturating_sub(max_tokens)
+        } else {
+            usize::MAX
+        };
+        if !args.no_history && args.daemon && args.llm_history_tokens > 0 {
+            // history limit on the non-system messages, on top of the system prompt
+            let system_tokens = token_counter.count_messages(
+                &messages
+                    .iter()
+                    .filter(|m| m.role == "system")
+                    .cloned()
+                    .collect::<Vec<_>>(),
             );
+            token_budget = token_budget.min(system_tokens + args.llm_history_tokens);
+        }
 
-            // Reverse iterate to trim from the end
-            for message in non_system_messages.iter_mut().rev() {
-                let message_size = message.content.len();
-                if excess_size == 0 {
-                    break;
-                }
-
-                if message_size <= excess_size {
-                    // Remove the whole message content if it's smaller than or equal to the excess
-                    excess_size -= message_size;
-                    message.content.clear();
+        if messages_tokens > token_budget && args.llm_history_summarize {
+            // keep the story going by folding older turns into the memory
+            match summarize_history(
+                &llm_backend,
+                &mut messages,
+                &token_counter,
+                if context_size > 0 {
+                    context_size.saturating_sub(args.llm_summary_max_tokens)
                 } else {
-                    // Truncate the message content to fit within the limit
-                    let new_size = message_size - excess_size;
-                    message.content = message.content.chars().take(new_size).collect();
-                    break; // After truncation, we should be within the limit
+                    token_budget
+                },
+                args.llm_history_keep,
+                &args.llm_summary_prompt,
+                GenerationParams::from_args(&args, args.llm_summary_max_tokens),
+            )
+            .await
+            {
+                Ok(summarized) => {
+                    debug!("Summarized {} messages into memory.", summarized);
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to summarize history, dropping oldest messages: {}",
+                        e
+                    );
                 }
             }
-
-            info!(
-                "Pruning complete. New history size: {} bytes for {} messages.",
-                non_system_messages
-                    .iter()
-                    .map(|m| m.content.len())
-                    .sum::<usize>(),
-                non_system_messages.len()
-            );
         }
 
-        // Reassemble messages, ensuring system messages are preserved at their original position
-        messages = system_messages
-            .into_iter()
-            .chain(non_system_messages.into_iter())
-            .collect();
-
-        let adjusted_messages_size = messages.iter().map(|m| m.content.len()).sum::<usize>();
-        if messages_size != adjusted_messages_size {
-            debug!(
-                "Messages size (bytes of content) adjusted from {} to {} for {} messages.",
-                messages_size,
-                adjusted_messages_size,
+        if token_counter.count_messages(&messages) > token_budget {
+            let dropped = trim_messages(&mut messages, &token_counter, token_budget);
+            info!(
+                "Pruned {} oldest messages, history is now {} tokens of {} for {} messages.",
+                dropped,
+                token_counter.count_messages(&messages),
+                token_budget,
                 messages.len()
             );
         } else {
             debug!(
-                "Messages size {} for {} messages.",
-                messages_size,
+                "Messages tokens {} for {} messages.",
+                messages_tokens,
                 messages.len()
             );
         }
@@ -1100,10 +1380,10 @@ async fn main() {
             debug!("Message History:");
             for (i, message) in messages.iter().enumerate() {
                 debug!(
-                    "Message {} - Role: {}, Size: {}",
+                    "Message {} - Role: {}, Tokens: {}",
                     i + 1,
                     message.role,
-                    message.content.len()
+                    token_counter.count_message(message)
                 );
             }
         }
@@ -1111,85 +1391,38 @@ async fn main() {
         // Setup mpsc channels for internal communication within the llm function
         let (external_sender, mut external_receiver) = tokio::sync::mpsc::channel::<String>(32768);
 
-        let model_id = args.model_id.clone();
-
         iterations += 1;
 
-        // Spawn a thread to run the LLM function, to keep the UI responsive streaming the response
-
         // Capture the start time for performance metrics
         let start = Instant::now();
 
-        let prompt = format_messages_for_llm(messages.clone(), args.chat_format.clone());
-
-        info!("\nPrompt: {}", prompt);
-
-        // Spawn a thread to run the mistral function, to keep the UI responsive
-        if args.candle_llm != "mistral" && args.candle_llm != "gemma" {
-            // exit if the LLM is not supported
-            error!("The specified LLM is not supported. Exiting...");
-            std::process::exit(1);
+        // show a vision LLM the current frame with this turn only, the history stays text
+        let mut request_messages = messages.clone();
+        if !args.llm_image.is_empty() && llm_backend.supports_images() {
+            let image = if args.llm_image != "sd" {
+                Some(ImageUrl::from_file(&args.llm_image).map_err(|e| e.to_string()))
+            } else if sd_frame_ready.load(Ordering::SeqCst) {
+                // encode a copy, the pipeline keeps updating the frames meanwhile
+                let frame = last_images.lock().await.last().cloned();
+                frame.map(|frame| ImageUrl::from_image(frame).map_err(|e| e.to_string()))
+            } else {
+                debug!("No Stable Diffusion frame yet, sending the prompt without an image.");
+                None
+            };
+            match (image, request_messages.last_mut()) {
+                (Some(Ok(image)), Some(last)) if last.role == "user" => last.images.push(image),
+                (Some(Ok(_)), _) => debug!("No user message to attach the image to."),
+                (Some(Err(e)), _) => error!("Failed to load the image {}: {}", args.llm_image, e),
+                (None, _) => {}
+            }
         }
 
-        let messages_clone = messages.clone();
-        let llm_host_clone = llm_host.clone();
-        let llm_path_clone = args.llm_path.clone();
-        let model_clone = args.model.clone();
-
-        let prompt_clone = prompt.clone();
-        let llm_thread = if args.use_api || args.use_openai {
-            tokio::spawn(async move {
-                let open_ai_request = OpenAIRequest {
-                    model: &model_clone,
-                    max_tokens: &max_tokens,
-                    messages: messages_clone,
-                    temperature: &args.temperature,
-                    top_p: &args.top_p,
-                    presence_penalty: &args.presence_penalty,
-                    frequency_penalty: &args.frequency_penalty,
-                    stream: &(args.no_stream == false),
-                };
-
-                stream_completion(
-                    open_ai_request,
-                    &openai_key.clone(),
-                    &llm_host_clone,
-                    &llm_path_clone,
-                    args.debug_inline,
-                    args.show_output_errors,
-                    external_sender,
-                )
-                .await;
-            })
-        } else if args.candle_llm == "mistral" {
-            tokio::spawn(async move {
-                let mistral_clone = mistral.clone();
-                if let Err(e) = mistral_clone(
-                    prompt_clone,
-                    max_tokens as usize,
-                    args.temperature as f64,
-                    args.quantized,
-                    Some(model_id),
-                    external_sender,
-                ) {
-                    eprintln!("Error running mistral: {}", e);
-                }
-            })
-        } else {
-            tokio::spawn(async move {
-                let gemma_clone = gemma.clone();
-                if let Err(e) = gemma_clone(
-                    prompt_clone,
-                    max_tokens as usize,
-                    args.temperature as f64,
-                    args.quantized,
-                    Some(model_id),
-                    external_sender,
-                ) {
-                    eprintln!("Error running gemma: {}", e);
-                }
-            })
-        };
+        // Spawn a thread to run the LLM backend, to keep the UI responsive streaming the response
+        let llm_thread = llm_backend.generate(
+            request_messages,
+            GenerationParams::from_args(&args, max_tokens),
+            external_sender,
+        );
 
         // Count tokens and collect output
         let mut token_count = 0;
@@ -1208,8 +1441,7 @@ async fn main() {
             && args.sd_image
             && (args.tts_enable || args.oai_tts || args.mimic3_tts)
         {
-            let mut sd_config = SDConfig::new();
-            sd_config.prompt = query.clone();
+            let mut sd_config = SDConfig::from_args(&args, query.clone());
             // reduce prompt down to 300 characters max
             if sd_config.prompt.len() > 300 {
                 sd_config.prompt = sd_config.prompt.chars().take(300).collect();
@@ -1218,32 +1450,6 @@ async fn main() {
             if query.len() > 300 {
                 sd_config.prompt.push_str("...");
             }
-            sd_config.height = Some(args.sd_height);
-            sd_config.width = Some(args.sd_width);
-            sd_config.image_position = Some(args.image_alignment.clone());
-            sd_config.intermediary_images = args.sd_intermediary_images;
-            sd_config.custom_model = Some(args.sd_custom_model.clone());
-            // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-            sd_config.sd_version = if args.sd_model == "1.5" {
-                StableDiffusionVersion::V1_5
-            } else if args.sd_model == "2.1" {
-                StableDiffusionVersion::V2_1
-            } else if args.sd_model == "xl" {
-                StableDiffusionVersion::Xl
-            } else if args.sd_model == "turbo" {
-                StableDiffusionVersion::Turbo
-            } else if args.sd_model == "Custom" {
-                StableDiffusionVersion::Custom
-            } else {
-                StableDiffusionVersion::V1_5
-            };
-            if args.sd_scaled_height > 0 {
-                sd_config.scaled_height = Some(args.sd_scaled_height);
-            }
-            if args.sd_scaled_width > 0 {
-                sd_config.scaled_width = Some(args.sd_scaled_width);
-            }
-            sd_config.n_steps = args.sd_n_steps;
             // just send a message with the last_message field true to indicate the end of the response
             let message_data_for_pipeline = MessageData {
                 paragraph: query.clone().to_string(),
@@ -1372,40 +1578,12 @@ async fn main() {
                         let paragraph_clone = paragraphs[paragraph_count].clone();
                         let output_id_clone = output_id.clone();
                         let mimic3_voice = args.mimic3_voice.clone().to_string();
-                        let image_alignment = args.image_alignment.clone();
                         let subtitle_position = args.subtitle_position.clone();
                         let args = args.clone();
 
                         let pipeline_task_sender_clone = pipeline_task_sender.clone();
 
-                        let mut sd_config = SDConfig::new();
-                        sd_config.prompt = paragraph_clone;
-                        sd_config.height = Some(args.sd_height);
-                        sd_config.width = Some(args.sd_width);
-                        sd_config.image_position = Some(image_alignment);
-                        sd_config.intermediary_images = args.sd_intermediary_images;
-                        sd_config.custom_model = Some(args.sd_custom_model.clone());
-                        if args.sd_scaled_height > 0 {
-                            sd_config.scaled_height = Some(args.sd_scaled_height);
-                        }
-                        if args.sd_scaled_width > 0 {
-                            sd_config.scaled_width = Some(args.sd_scaled_width);
-                        }
-                        // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-                        sd_config.sd_version = if args.sd_model == "1.5" {
-                            StableDiffusionVersion::V1_5
-                        } else if args.sd_model == "2.1" {
-                            StableDiffusionVersion::V2_1
-                        } else if args.sd_model == "xl" {
-                            StableDiffusionVersion::Xl
-                        } else if args.sd_model == "turbo" {
-                            StableDiffusionVersion::Turbo
-                        } else if args.sd_model == "babes" {
-                            StableDiffusionVersion::Custom
-                        } else {
-                            StableDiffusionVersion::V1_5
-                        };
-                        sd_config.n_steps = args.sd_n_steps;
+                        let sd_config = SDConfig::from_args(&args, paragraph_clone);
 
                         let args_clone = args.clone();
                         let mimic3_voice_clone = mimic3_voice.clone();
@@ -1474,40 +1652,12 @@ async fn main() {
                 let paragraph_clone = paragraph_text.clone();
                 let output_id_clone = output_id.clone();
                 let mimic3_voice = args.mimic3_voice.clone().to_string();
-                let image_alignment = args.image_alignment.clone();
                 let subtitle_position = args.subtitle_position.clone();
                 let args = args.clone();
 
                 let pipeline_task_sender_clone = pipeline_task_sender.clone();
 
-                let mut sd_config = SDConfig::new();
-                sd_config.prompt = paragraph_clone;
-                sd_config.height = Some(args.sd_height);
-                sd_config.width = Some(args.sd_width);
-                sd_config.image_position = Some(image_alignment);
-                sd_config.intermediary_images = args.sd_intermediary_images;
-                sd_config.custom_model = Some(args.sd_custom_model.clone());
-                if args.sd_scaled_height > 0 {
-                    sd_config.scaled_height = Some(args.sd_scaled_height);
-                }
-                if args.sd_scaled_width > 0 {
-                    sd_config.scaled_width = Some(args.sd_scaled_width);
-                }
-                // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-                sd_config.sd_version = if args.sd_model == "1.5" {
-                    StableDiffusionVersion::V1_5
-                } else if args.sd_model == "2.1" {
-                    StableDiffusionVersion::V2_1
-                } else if args.sd_model == "xl" {
-                    StableDiffusionVersion::Xl
-                } else if args.sd_model == "turbo" {
-                    StableDiffusionVersion::Turbo
-                } else if args.sd_model == "babes" {
-                    StableDiffusionVersion::Custom
-                } else {
-                    StableDiffusionVersion::V1_5
-                };
-                sd_config.n_steps = args.sd_n_steps;
+                let sd_config = SDConfig::from_args(&args, paragraph_clone);
 
                 let args_clone = args.clone();
                 let mimic3_voice_clone = mimic3_voice.clone();
@@ -1540,34 +1690,7 @@ async fn main() {
 
         // End of the response message to the pipeline
         if args.sd_image || args.tts_enable || args.oai_tts || args.mimic3_tts {
-            let mut sd_config = SDConfig::new();
-            sd_config.prompt = args.greeting.clone();
-            sd_config.height = Some(args.sd_height);
-            sd_config.width = Some(args.sd_width);
-            sd_config.image_position = Some(args.image_alignment.clone());
-            sd_config.intermediary_images = args.sd_intermediary_images;
-            sd_config.custom_model = Some(args.sd_custom_model.clone());
-            if args.sd_scaled_height > 0 {
-                sd_config.scaled_height = Some(args.sd_scaled_height);
-            }
-            if args.sd_scaled_width > 0 {
-                sd_config.scaled_width = Some(args.sd_scaled_width);
-            }
-            // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
-            sd_config.sd_version = if args.sd_model == "1.5" {
-                StableDiffusionVersion::V1_5
-            } else if args.sd_model == "2.1" {
-                StableDiffusionVersion::V2_1
-            } else if args.sd_model == "xl" {
-                StableDiffusionVersion::Xl
-            } else if args.sd_model == "turbo" {
-                StableDiffusionVersion::Turbo
-            } else if args.sd_model == "babes" {
-                StableDiffusionVersion::Custom
-            } else {
-                StableDiffusionVersion::V1_5
-            };
-            sd_config.n_steps = args.sd_n_steps;
+            let sd_config = SDConfig::from_args(&args, args.greeting.clone());
             // just send a message with the last_message field true to indicate the end of the response
             let message_data_for_pipeline = MessageData {
                 paragraph: args.greeting.to_string(),
@@ -1596,7 +1719,21 @@ async fn main() {
         }
         info!("Waiting for LLM thread to finish...");
         // Wait for the LLM thread to finish
-        llm_thread.await.unwrap();
+        let llm_failed = match llm_thread.await {
+            Ok(Ok(usage)) => {
+                session_usage.add(&usage);
+                session_usage.log();
+                false
+            }
+            Ok(Err(e)) => {
+                error!("Error running {} LLM: {}", llm_backend.name(), e);
+                true
+            }
+            Err(e) => {
+                error!("LLM thread failed: {}", e);
+                true
+            }
+        };
         info!("LLM thread finished.");
 
         // Calculate elapsed time and tokens per second
@@ -1618,8 +1755,18 @@ async fn main() {
         );
         println!("============= END RESPONSE ============");
 
-        // check if we got any tokens, if not clear and reset message history
-        if token_count == 0 {
+        // a failed call skips this turn and keeps the history for the next one
+        if llm_failed {
+            error!(
+                "Skipping iteration {}, the LLM call failed after retries.",
+                iterations
+            );
+            // drop the unanswered prompt so the roles keep alternating
+            if messages.last().map_or(false, |m| m.role == "user") {
+                messages.pop();
+            }
+        } else if token_count == 0 {
+            // check if we got any tokens, if not clear and reset message history
             messages.clear();
             messages.push(system_message.clone());
         } else {
@@ -1627,7 +1774,30 @@ async fn main() {
             messages.push(Message {
                 role: "assistant".to_string(),
                 content: answers_str.clone(),
+                ..Default::default()
             });
+            // twitch and control panel turns are not stories of the segment
+            if !twitch_query && !control_query {
+                if let Some(schedule) = schedule.as_mut() {
+                    schedule.story_done();
+                }
+            }
+        }
+
+        // persist the session each turn so a restart continues the show
+        if let Some(session) = session.as_mut() {
+            session.messages = messages.clone();
+            session.iterations = iterations;
+            session.paragraph_count = total_paragraph_count;
+            session.usage = session_usage.clone();
+            if let Err(e) = session.save(&args.session_dir) {
+                error!("Failed to save session {}: {}", session.name, e);
+            }
+            if !args.session_export.is_empty() {
+                if let Err(e) = session.export(&args.session_export) {
+                    error!("Failed to export session transcript: {}", e);
+                }
+            }
         }
 
         #[cfg(feature = "ndi")]
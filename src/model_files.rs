@@ -0,0 +1,123 @@
+/*
+ * model_files.rs
+ * --------------
+ * Local model file locations for offline hosts, when any path is given
+ * the candle backends load from disk and never touch the huggingface hub.
+*/
+
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Local model directory and optional per-file overrides.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ModelFiles {
+    pub model_dir: Option<String>,
+    pub tokenizer_file: Option<String>,
+    pub config_file: Option<String>,
+    pub weight_files: Option<String>,
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+impl ModelFiles {
+    /// Build from command line strings, empty strings are unset.
+    pub fn new(
+        model_dir: &str,
+        tokenizer_file: &str,
+        config_file: &str,
+        weight_files: &str,
+    ) -> Self {
+        ModelFiles {
+            model_dir: non_empty(model_dir),
+            tokenizer_file: non_empty(tokenizer_file),
+            config_file: non_empty(config_file),
+            weight_files: non_empty(weight_files),
+        }
+    }
+
+    /// True when any local path is set, the hub is not used at all then.
+    pub fn is_local(&self) -> bool {
+        self.model_dir.is_some()
+            || self.tokenizer_file.is_some()
+            || self.config_file.is_some()
+            || self.weight_files.is_some()
+    }
+
+    fn dir(&self) -> PathBuf {
+        PathBuf::from(self.model_dir.as_deref().unwrap_or("."))
+    }
+
+    /// The explicit file if given, else `name` inside the model directory.
+    pub fn file(&self, explicit: &Option<String>, name: &str) -> PathBuf {
+        match explicit {
+            Some(file) => PathBuf::from(file),
+            None => self.dir().join(name),
+        }
+    }
+
+    pub fn tokenizer(&self) -> PathBuf {
+        self.file(&self.tokenizer_file, "tokenizer.json")
+    }
+
+    pub fn config(&self) -> PathBuf {
+        self.file(&self.config_file, "config.json")
+    }
+
+    /// Weight files, the explicit comma separated list, else the gguf file when given,
+    /// else the safetensors in the model directory (sharded with an index or a single file).
+    pub fn weights(&self, gguf_name: Option<&str>) -> Result<Vec<PathBuf>> {
+        if let Some(files) = &self.weight_files {
+            return Ok(files.split(',').map(PathBuf::from).collect());
+        }
+        if let Some(gguf_name) = gguf_name {
+            return Ok(vec![self.dir().join(gguf_name)]);
+        }
+        let index = self.dir().join("model.safetensors.index.json");
+        if index.exists() {
+            local_safetensors_index(&self.dir(), &index)
+        } else {
+            Ok(vec![self.dir().join("model.safetensors")])
+        }
+    }
+}
+
+// Same as the hub index loader, the shard names listed in the weight map
+fn local_safetensors_index(dir: &Path, index: &Path) -> Result<Vec<PathBuf>> {
+    let json: serde_json::Value = serde_json::from_reader(std::fs::File::open(index)?)?;
+    let weight_map = match json.get("weight_map") {
+        Some(serde_json::Value::Object(map)) => map,
+        _ => anyhow::bail!("no weight map in {}", index.display()),
+    };
+    let mut shards = BTreeSet::new();
+    for value in weight_map.values() {
+        if let Some(file) = value.as_str() {
+            shards.insert(file.to_string());
+        }
+    }
+    Ok(shards.into_iter().map(|file| dir.join(file)).collect())
+}
+
+/// Error listing every missing file, so an offline install can be fixed in one pass.
+pub fn require_files(model: &str, files: &[PathBuf]) -> Result<()> {
+    let missing: Vec<String> = files
+        .iter()
+        .filter(|file| !file.exists())
+        .map(|file| file.display().to_string())
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{} local model files are missing: {}",
+            model,
+            missing.join(", ")
+        ))
+    }
+}
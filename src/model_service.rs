@@ -0,0 +1,230 @@
+/*
+ * model_service.rs
+ * ----------------
+ * Keeps candle models loaded in memory and serves generation requests
+ * over a bounded queue, so each chat reply doesn't pay the model load time.
+*/
+
+use crate::candle_gemma::GemmaModel;
+use crate::candle_llm::{CandleLlmModel, LlmFamily};
+use crate::candle_mistral::MistralModel;
+use crate::llm_backend::GenerationParams;
+use crate::model_files::ModelFiles;
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc::{self, error::TrySendError, Sender};
+use tokio::sync::oneshot;
+
+/// Which local model to load, services are shared by equal configs.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CandleModelConfig {
+    pub candle_llm: String,
+    pub model_id: String,
+    pub quantized: bool,
+    pub model_files: ModelFiles,
+}
+
+enum CandleModel {
+    Mistral(MistralModel),
+    Gemma(GemmaModel),
+    Llm(CandleLlmModel),
+}
+
+impl CandleModel {
+    fn load(config: &CandleModelConfig) -> Result<Self> {
+        let model_id = Some(config.model_id.clone());
+        match config.candle_llm.as_str() {
+            "mistral" => Ok(CandleModel::Mistral(MistralModel::load(
+                config.quantized,
+                model_id,
+                &config.model_files,
+            )?)),
+            "gemma" => Ok(CandleModel::Gemma(GemmaModel::load(
+                config.quantized,
+                model_id,
+                &config.model_files,
+            )?)),
+            other => match LlmFamily::from_name(other) {
+                Some(family) => Ok(CandleModel::Llm(CandleLlmModel::load(
+                    family,
+                    config.quantized,
+                    model_id,
+                    &config.model_files,
+                )?)),
+                None => Err(anyhow!(
+                    "The specified LLM {} is not supported",
+                    config.candle_llm
+                )),
+            },
+        }
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        match self {
+            CandleModel::Mistral(m) => m.tokenizer(),
+            CandleModel::Gemma(m) => m.tokenizer(),
+            CandleModel::Llm(m) => m.tokenizer(),
+        }
+    }
+
+    async fn generate(
+        &mut self,
+        prompt: &str,
+        params: &GenerationParams,
+        sender: Sender<String>,
+    ) -> Result<()> {
+        match self {
+            CandleModel::Mistral(m) => m.generate(prompt, params, sender).await,
+            CandleModel::Gemma(m) => m.generate(prompt, params, sender).await,
+            CandleModel::Llm(m) => m.generate(prompt, params, sender).await,
+        }
+    }
+}
+
+struct GenerationRequest {
+    prompt: String,
+    params: GenerationParams,
+    sender: Sender<String>,
+    done: oneshot::Sender<Result<()>>,
+}
+
+pub struct ModelService {
+    config: CandleModelConfig,
+    queue_size: usize,
+    requests: Sender<GenerationRequest>,
+    tokenizer: Arc<OnceLock<Arc<Tokenizer>>>,
+}
+
+// Loaded models shared between the main loop and twitch chat
+static MODEL_SERVICES: Lazy<Mutex<HashMap<CandleModelConfig, Arc<ModelService>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl ModelService {
+    /// Start loading the model in the background and serve requests once it is ready.
+    pub fn start(config: CandleModelConfig, queue_size: usize) -> Arc<Self> {
+        let queue_size = queue_size.max(1);
+        let (requests, mut receiver) = mpsc::channel::<GenerationRequest>(queue_size);
+
+        let tokenizer = Arc::new(OnceLock::new());
+        let loaded_tokenizer = tokenizer.clone();
+        let load_config = config.clone();
+        // candle forward passes block, so the model runs on its own thread
+        // instead of holding an async worker for each generation
+        let spawned = std::thread::Builder::new()
+            .name(format!("model-{}", config.candle_llm))
+            .spawn(move || {
+                let start = std::time::Instant::now();
+                let mut model = match CandleModel::load(&load_config) {
+                    Ok(model) => {
+                        info!(
+                            "ModelService: {} {} loaded in {:?}",
+                            load_config.candle_llm,
+                            load_config.model_id,
+                            start.elapsed()
+                        );
+                        let _ = loaded_tokenizer.set(Arc::new(model.tokenizer().clone()));
+                        model
+                    }
+                    Err(e) => {
+                        // keep answering so callers get the load error instead of hanging
+                        let message = format!(
+                            "failed to load {} {}: {}",
+                            load_config.candle_llm, load_config.model_id, e
+                        );
+                        error!("ModelService: {}", message);
+                        while let Some(request) = receiver.blocking_recv() {
+                            let _ = request.done.send(Err(anyhow!(message.clone())));
+                        }
+                        return;
+                    }
+                };
+
+                while let Some(request) = receiver.blocking_recv() {
+                    let result = futures::executor::block_on(model.generate(
+                        &request.prompt,
+                        &request.params,
+                        request.sender,
+                    ));
+                    let _ = request.done.send(result);
+                }
+            });
+        if let Err(e) = spawned {
+            // the receiver is dropped with the closure, so requests fail as stopped
+            error!("ModelService: failed to start the model thread: {}", e);
+        }
+
+        Arc::new(ModelService {
+            config,
+            queue_size,
+            requests,
+            tokenizer,
+        })
+    }
+
+    /// Get the running service for this model, starting it on first use.
+    pub fn shared(config: CandleModelConfig, queue_size: usize) -> Arc<Self> {
+        let mut services = MODEL_SERVICES.lock().unwrap();
+        services
+            .entry(config.clone())
+            .or_insert_with(|| ModelService::start(config, queue_size))
+            .clone()
+    }
+
+    /// Config of a service already running this model, None when none was started.
+    pub fn running(candle_llm: &str) -> Option<CandleModelConfig> {
+        let services = MODEL_SERVICES.lock().unwrap();
+        services
+            .keys()
+            .find(|config| config.candle_llm == candle_llm)
+            .cloned()
+    }
+
+    pub fn config(&self) -> &CandleModelConfig {
+        &self.config
+    }
+
+    /// The model tokenizer, None until the model has finished loading.
+    pub fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        self.tokenizer.get().cloned()
+    }
+
+    /// Queue a generation request, fails right away if the queue is full.
+    /// Tokens are streamed over the sender and this returns when generation is finished.
+    pub async fn generate(
+        &self,
+        prompt: String,
+        params: GenerationParams,
+        sender: Sender<String>,
+    ) -> Result<()> {
+        let (done, done_receiver) = oneshot::channel();
+        let request = GenerationRequest {
+            prompt,
+            params,
+            sender,
+            done,
+        };
+        match self.requests.try_send(request) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                return Err(anyhow!(
+                    "{} model queue is full ({} requests waiting)",
+                    self.config.candle_llm,
+                    self.queue_size
+                ))
+            }
+            Err(TrySendError::Closed(_)) => {
+                return Err(anyhow!("{} model service stopped", self.config.candle_llm))
+            }
+        }
+        done_receiver.await.map_err(|_| {
+            anyhow!(
+                "{} model service dropped the request",
+                self.config.candle_llm
+            )
+        })?
+    }
+}
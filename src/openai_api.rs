@@ -3,30 +3,266 @@ Implement the OpenAI API generically for any LLM following it
 Chris Kennedy @2024 MIT license
 */
 
+use crate::count_tokens;
+use crate::sse::SseDecoder;
+use crate::tools::ToolDefinition;
+use base64::engine::general_purpose;
+use base64::Engine;
 use bytes::Bytes;
 use chrono::{TimeZone, Utc};
-use log::{debug, error, info};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use log::{debug, error, info, warn};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response};
+use serde::{Deserialize, Serialize, Serializer};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{self};
 
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
 pub struct Message {
     pub role: String,
     pub content: String,
+    /// Tools the assistant asked to call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Which tool call a "tool" role message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Images sent after the text to vision models, dropped by text only backends
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageUrl>,
+}
+
+impl Message {
+    /// Content in the OpenAI content array format, the text then the images.
+    pub fn content_parts(&self) -> Vec<ContentPart> {
+        let mut parts = Vec::new();
+        if !self.content.is_empty() {
+            parts.push(ContentPart::Text {
+                text: self.content.clone(),
+            });
+        }
+        for image in &self.images {
+            parts.push(ContentPart::ImageUrl {
+                image_url: image.clone(),
+            });
+        }
+        parts
+    }
+}
+
+/// Part of a multimodal message content.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// Image as an http(s) url or a base64 data url.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ImageUrl {
+    pub url: String,
+    /// "low", "high" or "auto" image resolution for the model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+// Largest image side sent to the LLM, vision models downscale anything bigger anyway
+const MAX_IMAGE_SIZE: u32 = 1024;
+
+impl ImageUrl {
+    pub fn new(url: &str) -> Self {
+        ImageUrl {
+            url: url.to_string(),
+            detail: None,
+        }
+    }
+
+    /// Data url of a frame, scaled down and encoded as JPEG.
+    pub fn from_image(image: RgbImage) -> Result<Self, image::ImageError> {
+        let mut image = DynamicImage::ImageRgb8(image);
+        if image.width() > MAX_IMAGE_SIZE || image.height() > MAX_IMAGE_SIZE {
+            image = image.thumbnail(MAX_IMAGE_SIZE, MAX_IMAGE_SIZE);
+        }
+        let mut jpeg = Cursor::new(Vec::new());
+        image.write_to(&mut jpeg, ImageOutputFormat::Jpeg(85))?;
+        Ok(ImageUrl::new(&format!(
+            "data:image/jpeg;base64,{}",
+            general_purpose::STANDARD.encode(jpeg.into_inner())
+        )))
+    }
+
+    /// Data url of an image file, such as a stream thumbnail written by another process.
+    pub fn from_file(path: &str) -> std::io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        let mime = match extension.as_str() {
+            "png" => "image/png",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            _ => "image/jpeg",
+        };
+        Ok(ImageUrl::new(&format!(
+            "data:{};base64,{}",
+            mime,
+            general_purpose::STANDARD.encode(bytes)
+        )))
+    }
+}
+
+// Message as sent to the API, the content is an array only when there are images
+#[derive(Serialize)]
+struct ApiMessage<'a> {
+    role: &'a str,
+    content: ApiContent<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<&'a Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ApiContent<'a> {
+    Text(&'a str),
+    Parts(Vec<ContentPart>),
+}
+
+fn serialize_messages<S: Serializer>(
+    messages: &[Message],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(messages.iter().map(|message| ApiMessage {
+        role: &message.role,
+        content: if message.images.is_empty() {
+            ApiContent::Text(&message.content)
+        } else {
+            ApiContent::Parts(message.content_parts())
+        },
+        tool_calls: message.tool_calls.as_ref(),
+        tool_call_id: message.tool_call_id.as_ref(),
+    }))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub call_type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments
+    pub arguments: String,
 }
 
 #[derive(Serialize)]
 pub struct OpenAIRequest<'a> {
     pub model: &'a str,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "serialize_messages"
+    )]
     pub messages: Vec<Message>,
+    /// Raw prompt for /v1/completions, sent instead of the messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
     pub max_tokens: &'a usize,      // add this field to the request struct
     pub temperature: &'a f32,       // add this field to the request struct
     pub top_p: &'a f32,             // add this field to the request struct
     pub presence_penalty: &'a f32,  // add this field to the request struct
     pub frequency_penalty: &'a f32, // add this field to the request struct
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    pub stop: &'a [String],
+    #[serde(skip_serializing_if = "<[ToolDefinition]>::is_empty")]
+    pub tools: &'a [ToolDefinition],
     pub stream: &'a bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+}
+
+#[derive(Serialize)]
+pub struct StreamOptions {
+    /// Ask for a last chunk with the token usage of the request
+    pub include_usage: bool,
+}
+
+/// Token usage reported by the server.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ApiUsage {
+    #[serde(default)]
+    pub prompt_tokens: usize,
+    #[serde(default)]
+    pub completion_tokens: usize,
+}
+
+/// Result of a completion request, the content itself is sent over the channel.
+#[derive(Debug, Default)]
+pub struct Completion {
+    pub tool_calls: Vec<ToolCall>,
+    /// Usage from the server, None when it didn't report any
+    pub usage: Option<ApiUsage>,
+    /// Content chunks received, an estimate of the completion tokens
+    pub content_chunks: usize,
+    pub time_to_first_token: Option<Duration>,
+    /// Why the LLM stopped, "stop", "length" or "tool_calls"
+    pub finish_reason: Option<String>,
+}
+
+/// Whole chat completion returned when not streaming.
+#[derive(Deserialize)]
+struct ChatCompletion {
+    #[serde(default)]
+    choices: Vec<ChatCompletionChoice>,
+    usage: Option<ApiUsage>,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionChoice {
+    #[serde(default)]
+    message: ChatCompletionMessage,
+    /// Completions endpoint content, in place of the message
+    text: Option<String>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct ChatCompletionMessage {
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCall>>,
+}
+
+/// Error body, {"error": {"message": "...", "type": "...", "code": ...}}
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetail {
+    message: String,
+    #[serde(rename = "type")]
+    error_type: Option<String>,
+}
+
+// The error message of an OpenAI style error body, or the body as is
+fn error_message(body: &str) -> String {
+    match serde_json::from_str::<ErrorBody>(body) {
+        Ok(ErrorBody { error }) => match error.error_type {
+            Some(error_type) => format!("{} ({})", error.message, error_type),
+            None => error.message,
+        },
+        Err(_) => body.trim().to_string(),
+    }
 }
 
 #[derive(Deserialize)]
@@ -39,6 +275,7 @@ struct OpenAIResponse {
     choices: Option<Vec<Choice>>,
     content: Option<String>,
     system_fingerprint: Option<String>,
+    usage: Option<ApiUsage>,
 }
 
 #[derive(Deserialize)]
@@ -46,170 +283,205 @@ pub struct Choice {
     finish_reason: Option<String>,
     logprobs: Option<bool>,
     index: i32,
+    #[serde(default)]
     delta: Delta, // Use Option to handle cases where it might be null or missing
+    /// Completions endpoint content, in place of the delta
+    text: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Default)]
 pub struct Delta {
     content: Option<String>,
+    tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
-pub fn format_messages_for_llm(messages: Vec<Message>, chat_format: String) -> String {
-    let mut formatted_history = String::new();
-    // Begin/End Stream Tokens
-    let eos_token = if chat_format == "llama2" { "</s>" } else { "" };
-    let bos_token = if chat_format == "llama2" { "<s>" } else { "" };
-    // Instruction Tokens
-    let inst_token = if chat_format == "llama2" {
-        "[INST]"
-    } else if chat_format == "google" {
-        "<start_of_turn>"
-    } else if chat_format == "chatml" {
-        "<im_start>"
-    } else if chat_format == "vicuna" {
-        ""
-    } else {
-        ""
-    };
-    let inst_end_token = if chat_format == "llama2" {
-        "[/INST]"
-    } else if chat_format == "google" {
-        "<end_of_turn>"
-    } else if chat_format == "chatml" {
-        "<im_end>"
-    } else if chat_format == "vicuna" {
-        "\n"
-    } else {
-        ""
-    };
-    // Assistant Tokens
-    let assist_token = if chat_format == "llama2" {
-        ""
-    } else if chat_format == "google" {
-        "<start_of_turn>"
-    } else if chat_format == "chatml" {
-        "<im_start>"
-    } else if chat_format == "vicuna" {
-        ""
-    } else {
-        ""
-    };
-    let assist_end_token = if chat_format == "llama2" {
-        ""
-    } else if chat_format == "google" {
-        "<end_of_turn>"
-    } else if chat_format == "chatml" {
-        "<im_end>"
-    } else if chat_format == "vicuna" {
-        "\n"
-    } else {
-        ""
-    };
-    // System Tokens
-    let sys_token = if chat_format == "llama2" {
-        "<<SYS>>"
-    } else if chat_format == "google" {
-        "<start_of_turn>"
-    } else if chat_format == "chatml" {
-        "<im_start>"
-    } else if chat_format == "vicuna" {
-        ""
-    } else {
-        ""
-    };
-    let sys_end_token = if chat_format == "llama2" {
-        "<</SYS>>"
-    } else if chat_format == "google" {
-        "<end_of_turn>"
-    } else if chat_format == "chatml" {
-        "<im_end>"
-    } else if chat_format == "vicuna" {
-        "\n"
-    } else {
-        ""
-    };
-    // Names
-    let sys_name = if chat_format == "llama2" {
-        ""
-    } else if chat_format == "google" {
-        "model"
-    } else if chat_format == "chatml" {
-        "system"
-    } else if chat_format == "vicuna" {
-        "System: "
-    } else {
-        ""
-    };
-    let user_name = if chat_format == "llama2" {
-        ""
-    } else if chat_format == "google" {
-        "user"
-    } else if chat_format == "chatml" {
-        "user"
-    } else if chat_format == "vicuna" {
-        "User: "
-    } else {
-        ""
-    };
-    let assist_name = if chat_format == "llama2" {
-        ""
-    } else if chat_format == "google" {
-        "model"
-    } else if chat_format == "chatml" {
-        "assistant"
-    } else if chat_format == "vicuna" {
-        "Assistant: "
-    } else {
-        ""
-    };
+// Tool calls are streamed in pieces, the id and name first then the arguments
+#[derive(Debug, Deserialize)]
+pub struct ToolCallDelta {
+    #[serde(default)]
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionCallDelta>,
+}
 
-    for (index, message) in messages.iter().enumerate() {
-        // check if last message, safely get if this is the last message
-        let is_last = index == messages.len() - 1;
-        match message.role.as_str() {
-            // remove <|im_end|> from anywhere in message
-            "system" => {
-                let message_content = message.content.replace("<|im_end|>", "");
-                formatted_history += &format!(
-                    "{}{}{} {}{}{}\n",
-                    bos_token, sys_token, sys_name, message_content, sys_end_token, eos_token
-                );
+#[derive(Debug, Deserialize)]
+pub struct FunctionCallDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+// Reassemble the streamed tool call pieces by index
+fn merge_tool_call_deltas(tool_calls: &mut Vec<ToolCall>, deltas: &[ToolCallDelta]) {
+    for delta in deltas {
+        while tool_calls.len() <= delta.index {
+            tool_calls.push(ToolCall {
+                call_type: "function".to_string(),
+                ..Default::default()
+            });
+        }
+        let tool_call = &mut tool_calls[delta.index];
+        if let Some(id) = &delta.id {
+            tool_call.id.push_str(id);
+        }
+        if let Some(function) = &delta.function {
+            if let Some(name) = &function.name {
+                tool_call.function.name.push_str(name);
             }
-            "user" => {
-                // Assuming user messages should be formatted as instructions
-                let message_content = message.content.replace("<|im_end|>", "");
-                formatted_history += &format!(
-                    "{}{}{} {}{}\n",
-                    bos_token, inst_token, user_name, message_content, inst_end_token
-                );
+            if let Some(arguments) = &function.arguments {
+                tool_call.function.arguments.push_str(arguments);
             }
-            "assistant" => {
-                // Close the instruction tag for user/system messages and add the assistant's response
-                let message_content = message.content.replace("<|im_end|>", "");
-                if is_last {
-                    formatted_history += &format!(
-                        "{}{}{} {}\n",
-                        bos_token, assist_token, assist_name, message_content
-                    );
-                } else {
-                    formatted_history += &format!(
-                        "{}{}{} {}{}{}\n",
-                        bos_token,
-                        assist_token,
-                        assist_name,
-                        message_content,
-                        assist_end_token,
-                        eos_token
-                    );
-                }
+        }
+    }
+}
+
+/// Errors from an OpenAI compatible chat completions call.
+#[derive(Debug)]
+pub enum OpenAIError {
+    /// Connection failed or dropped
+    Network(String),
+    /// No response or no streamed data within the read timeout
+    Timeout(String),
+    /// Non-2xx status with the error body
+    Http {
+        status: u16,
+        body: String,
+        retry_after: Option<Duration>,
+    },
+    /// 429 Too Many Requests
+    RateLimited {
+        body: String,
+        retry_after: Option<Duration>,
+    },
+    /// The response could not be understood
+    Parse(String),
+}
+
+impl OpenAIError {
+    /// Worth trying again, network trouble, timeouts, rate limits and server errors.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            OpenAIError::Network(_) | OpenAIError::Timeout(_) | OpenAIError::RateLimited { .. } => {
+                true
             }
-            _ => {}
+            OpenAIError::Http { status, .. } => *status >= 500,
+            OpenAIError::Parse(_) => false,
+        }
+    }
+
+    /// Delay the server asked for with a Retry-After header.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            OpenAIError::Http { retry_after, .. }
+            | OpenAIError::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for OpenAIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenAIError::Network(e) => write!(f, "network error: {}", e),
+            OpenAIError::Timeout(e) => write!(f, "timeout: {}", e),
+            OpenAIError::Http { status, body, .. } => {
+                write!(f, "HTTP {}: {}", status, error_message(body))
+            }
+            OpenAIError::RateLimited { body, .. } => {
+                write!(f, "rate limited: {}", error_message(body))
+            }
+            OpenAIError::Parse(e) => write!(f, "parse error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OpenAIError {}
+
+/// Timeouts and retries for the chat completions client.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    pub connect_timeout: Duration,
+    /// Max wait for the response headers and between streamed chunks
+    pub read_timeout: Duration,
+    pub max_retries: u32,
+    /// First retry delay, doubled each retry unless the server sends Retry-After
+    pub retry_base_delay: Duration,
+    /// Longest wait before a retry, also caps the server's Retry-After
+    pub max_retry_delay: Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(120),
+            max_retries: 3,
+            retry_base_delay: Duration::from_secs(1),
+            max_retry_delay: Duration::from_secs(60),
         }
     }
+}
+
+impl ClientOptions {
+    /// Delay before retry number `attempt`, counting from 0.
+    pub fn retry_delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let delay = retry_after.unwrap_or_else(|| {
+            let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+            self.retry_base_delay.saturating_mul(factor)
+        });
+        delay.min(self.max_retry_delay)
+    }
+}
+
+// Retry-After is either delay seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    // a date in the past means retry now
+    Some(
+        (at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
 
-    //formatted_history += "Instructions: Use the previous converation between you the assitant and the user as context and to answer the last question asked by the User as the assitant.\nAssistant:";
+// Turn a non-2xx response into an error with its body
+async fn check_status(response: Response, read_timeout: Duration) -> Result<Response, OpenAIError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+    // a stalled error body is a timeout like a stalled stream
+    let body = match tokio::time::timeout(read_timeout, response.text()).await {
+        Ok(body) => body.unwrap_or_default(),
+        Err(_) => {
+            return Err(OpenAIError::Timeout(format!(
+                "no response body for {:?}",
+                read_timeout
+            )))
+        }
+    };
+    Err(status_error(status.as_u16(), body, retry_after))
+}
 
-    formatted_history
+// Error of a response with this status and error body
+fn status_error(status: u16, body: String, retry_after: Option<Duration>) -> OpenAIError {
+    if status == 429 {
+        OpenAIError::RateLimited { body, retry_after }
+    } else {
+        OpenAIError::Http {
+            status,
+            body,
+            retry_after,
+        }
+    }
 }
 
 /*
@@ -223,11 +495,15 @@ pub async fn stream_completion(
     openai_key: &str,
     llm_host: &str,
     llm_path: &str,
+    options: &ClientOptions,
     debug_inline: bool,
     show_output_errors: bool,
     external_sender: tokio::sync::mpsc::Sender<String>,
-) {
-    let client = Client::new();
+) -> Result<Completion, OpenAIError> {
+    let client = Client::builder()
+        .connect_timeout(options.connect_timeout)
+        .build()
+        .map_err(|e| OpenAIError::Network(e.to_string()))?;
 
     // measure messages member size of the content member of each pair of the messages array
     let mut prompt_token_count = 0;
@@ -236,19 +512,35 @@ pub async fn stream_completion(
     }
 
     let start_time = Instant::now();
-    let response = client
-        .post(format!("{}{}", llm_host, llm_path))
-        .header("Authorization", format!("Bearer {}", openai_key))
-        .json(&open_ai_request)
-        .send()
-        .await;
-
-    // handle errors
-    let mut response = match response {
-        Ok(resp) => resp,
-        Err(e) => {
-            println!("Error: {}", e);
-            return;
+    // retry until the response starts, nothing has been streamed out yet
+    let mut attempt = 0;
+    let mut response = loop {
+        let request = client
+            .post(format!("{}{}", llm_host, llm_path))
+            .header("Authorization", format!("Bearer {}", openai_key))
+            .json(&open_ai_request)
+            .send();
+        let result = match tokio::time::timeout(options.read_timeout, request).await {
+            Ok(Ok(response)) => check_status(response, options.read_timeout).await,
+            Ok(Err(e)) if e.is_timeout() => Err(OpenAIError::Timeout(e.to_string())),
+            Ok(Err(e)) => Err(OpenAIError::Network(e.to_string())),
+            Err(_) => Err(OpenAIError::Timeout(format!(
+                "no response within {:?}",
+                options.read_timeout
+            ))),
+        };
+        match result {
+            Ok(response) => break response,
+            Err(e) if e.is_retryable() && attempt < options.max_retries => {
+                let delay = options.retry_delay(attempt, e.retry_after());
+                attempt += 1;
+                warn!(
+                    "LLM request failed: {}, retry {}/{} in {:?}",
+                    e, attempt, options.max_retries, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
         }
     };
 
@@ -257,21 +549,55 @@ pub async fn stream_completion(
     let mut loop_count = 0;
 
     if !open_ai_request.stream {
-        info!("Response status: {}", response.status());
+        let status = response.status();
+        info!("Response status: {}", status);
         debug!("Headers: {:#?}", response.headers());
-        let text = match response.text().await {
-            Ok(text) => text,
-            Err(e) => {
-                eprintln!("Failed to get response text: {}", e);
-                return;
+        let text = match tokio::time::timeout(options.read_timeout, response.text()).await {
+            Ok(Ok(text)) => text,
+            Ok(Err(e)) => return Err(OpenAIError::Network(e.to_string())),
+            Err(_) => {
+                return Err(OpenAIError::Timeout(format!(
+                    "no response body for {:?}",
+                    options.read_timeout
+                )))
             }
         };
-        println!("\nLLM Response:\n  {}\n---\n", text);
-        // send back over mpsc channel
-        if let Err(e) = external_sender.send(text).await {
-            eprintln!("Failed to send text over mpsc channel: {}", e);
-            return;
+        debug!("\nLLM Response:\n  {}\n---\n", text);
+        let time_to_first_token = Some(start_time.elapsed());
+
+        // some servers answer errors with a 200 status and an error body, checked first
+        // since an error body also parses as a completion without choices
+        if serde_json::from_str::<ErrorBody>(&text).is_ok() {
+            return Err(status_error(status.as_u16(), text, None));
+        }
+        let chat_completion = match serde_json::from_str::<ChatCompletion>(&text) {
+            Ok(chat_completion) => chat_completion,
+            Err(e) => return Err(OpenAIError::Parse(format!("{} in response '{}'", e, text))),
+        };
+        let choice = match chat_completion.choices.into_iter().next() {
+            Some(choice) => choice,
+            None => return Err(OpenAIError::Parse("no choices in the response".to_string())),
+        };
+        let finish_reason = choice.finish_reason;
+        info!(
+            "Finish reason: {}",
+            finish_reason.as_deref().unwrap_or("unknown")
+        );
+
+        // only the assistant message content goes out, not the JSON around it
+        let content = choice.message.content.or(choice.text).unwrap_or_default();
+        if !content.is_empty() {
+            if let Err(e) = external_sender.send(content.clone()).await {
+                eprintln!("Failed to send text over mpsc channel: {}", e);
+            }
         }
+        Ok(Completion {
+            tool_calls: choice.message.tool_calls.unwrap_or_default(),
+            usage: chat_completion.usage,
+            content_chunks: count_tokens(&content),
+            time_to_first_token,
+            finish_reason,
+        })
     } else {
         // Create an mpsc channel
         let (tx, mut rx) = mpsc::channel::<Bytes>(32);
@@ -286,61 +612,64 @@ pub async fn stream_completion(
             let mut first_run = true;
             let mut add_newline = false;
             let mut add_space = false;
-            while let Some(chunk) = rx.recv().await {
-                loop_count += 1;
-
-                if first_run {
-                    // print headers properly without causing a borrow error
-                    debug!("Headers: {:#?}", headers);
-                    info!("Response status: {}", status);
-                }
+            let mut tool_calls: Vec<ToolCall> = Vec::new();
+            let mut parse_errors = 0;
+            let mut usage: Option<ApiUsage> = None;
+            let mut time_to_first_token: Option<Duration> = None;
+            let mut finish_reason: Option<String> = None;
+            let mut sse_decoder = SseDecoder::new();
+            let mut stream_done = false;
+            while !stream_done {
+                // events completed by the next chunk, or the unterminated last event at the end
+                let events = match rx.recv().await {
+                    Some(chunk) => {
+                        loop_count += 1;
+
+                        if first_run {
+                            // print headers properly without causing a borrow error
+                            debug!("Headers: {:#?}", headers);
+                            info!("Response status: {}", status);
+                        }
 
-                first_run = false;
+                        first_run = false;
 
-                debug!("#{} LLM Result Chunk: {:#?}\n", loop_count, chunk);
-                let chunk_vec = Vec::from(chunk.as_ref());
-                let chunk_str = match String::from_utf8(chunk_vec).ok() {
-                    Some(s) => s,
+                        debug!("#{} LLM Result Chunk: {:#?}\n", loop_count, chunk);
+                        sse_decoder.push(&chunk)
+                    }
                     None => {
-                        error!(
-                            "Invalid UTF-8 sequence, skipping chunk. {}/{:?}",
-                            chunk.len(),
-                            chunk
-                        );
-                        continue;
-                    } // skip non-UTF-8 chunks
+                        stream_done = true;
+                        sse_decoder.finish()
+                    }
                 };
-
-                // Splitting the chunk based on "data: " prefix to handle multiple JSON blobs
-                let json_blobs: Vec<&str> = chunk_str.split("\ndata: ").collect();
                 let mut blob_count = 0;
 
-                for json_blob in json_blobs.iter() {
+                for event in events.iter() {
                     blob_count += 1;
-                    debug!("Json Blob: {}/{} - {}", loop_count, blob_count, json_blob);
-                    if json_blob.is_empty() || *json_blob == "\n" {
-                        debug!("Empty line in response chunks.");
+                    debug!("SSE Event: {}/{} - {:?}", loop_count, blob_count, event);
+                    if event.data.is_empty() {
+                        debug!("Empty event in response chunks.");
                         continue;
                     }
 
-                    if json_blob == &"[DONE]" {
+                    if event.is_done() {
                         info!("End of response chunks.\n");
                         break;
                     }
 
-                    // Confirm we have a '{' at the start, or find the offset of first '{' character
-                    let offset = json_blob.find('{').unwrap_or(0);
-                    let response_json = &json_blob[offset..];
-
-                    if response_json.is_empty() {
-                        error!("Invalid response chunk:\n - '{}'", json_blob);
-                        continue;
-                    }
+                    let response_json = event.data.as_str();
 
                     debug!("Chunk #{} response: '{}'", loop_count, response_json);
 
                     match serde_json::from_str::<OpenAIResponse>(response_json) {
-                        Ok(res) => {
+                        Ok(mut res) => {
+                            // the usage chunk comes last and has no choices
+                            if let Some(reported) = res.usage.take() {
+                                usage = Some(reported);
+                                if res.choices.as_ref().map_or(true, |c| c.is_empty()) {
+                                    continue;
+                                }
+                            }
+
                             let content = match &res.content {
                                 Some(content) => content,
                                 None => "",
@@ -398,38 +727,6 @@ pub async fn stream_completion(
                                     None => "unknown".to_string(),
                                 };
 
-                                // check if we have a finish reason
-                                if let Some(reason) = &choice.finish_reason {
-                                    let end_time = Instant::now();
-                                    let mut duration = end_time.duration_since(start_time);
-                                    let pretty_time = format!("{:?}", duration);
-
-                                    // Ensure the duration is at least 1 second
-                                    if duration < std::time::Duration::new(1, 0) {
-                                        duration = std::time::Duration::new(1, 0);
-                                    }
-
-                                    debug!(
-                                         "\n--\nIndex {} ID {}\nObject {} by Model {} User {}\nCreated on {} Finish reason: {}\n {}/{}/{} Tokens/Prompt/Response {} Bytes at {}tps @ {}s.\n--\n",
-                                         choice.index,
-                                         id,
-                                         object,
-                                         model,
-                                         role,
-                                         created_date,
-                                         reason,
-                                         token_count + prompt_token_count,
-                                         prompt_token_count,
-                                         token_count,
-                                         byte_count,
-                                         token_count as u64 / duration.as_secs(),
-                                         pretty_time
-                                     );
-
-                                    // break the loop if we have a finish reason
-                                    break;
-                                }
-
                                 // check for system_fingerprint
                                 if let Some(fingerprint) = &res.system_fingerprint {
                                     debug!("\nSystem fingerprint: {}", fingerprint);
@@ -440,8 +737,15 @@ pub async fn stream_completion(
                                     println!("Logprobs: {}", logprobs);
                                 }
 
+                                // tool call pieces, the calls are run once the stream ends
+                                if let Some(deltas) = &choice.delta.tool_calls {
+                                    merge_tool_call_deltas(&mut tool_calls, deltas);
+                                }
+
                                 // check if we have content in the delta
-                                if let Some(content) = &choice.delta.content {
+                                if let Some(content) =
+                                    choice.delta.content.as_ref().or(choice.text.as_ref())
+                                {
                                     // if add_newline is true, add a new line before the content and set add_newline to false
                                     let content = if add_newline {
                                         add_newline = false;
@@ -458,25 +762,58 @@ pub async fn stream_completion(
                                     // check for one or more new lines and if so set add_newline to true
                                     if content.ends_with("\n") && content.trim() == "" {
                                         add_newline = true;
-                                        continue;
-                                    }
-
-                                    // check if contains only a space, if so set add_space to true
-                                    if content.trim() == "" {
+                                    } else if content.trim() == "" {
+                                        // contains only a space, so set add_space to true
                                         add_space = true;
-                                        continue;
+                                    } else {
+                                        if time_to_first_token.is_none() {
+                                            time_to_first_token = Some(start_time.elapsed());
+                                        }
+                                        token_count += 1;
+                                        byte_count += content.len();
+                                        if let Err(e) = etx.send(format!("{}", content)).await {
+                                            error!("Failed to send content: {}", e);
+                                        }
+
+                                        if let Err(e) =
+                                            external_sender.send(content.to_string()).await
+                                        {
+                                            error!(
+                                                "Failed to send content to external sender: {}",
+                                                e
+                                            );
+                                        }
                                     }
+                                }
 
-                                    token_count += 1;
-                                    byte_count += content.len();
-                                    if let Err(e) = etx.send(format!("{}", content)).await {
-                                        error!("Failed to send content: {}", e);
-                                    }
+                                // check if we have a finish reason, the chunk's text and tool calls are kept above
+                                if let Some(reason) = &choice.finish_reason {
+                                    finish_reason = Some(reason.clone());
+                                    let end_time = Instant::now();
+                                    let mut duration = end_time.duration_since(start_time);
+                                    let pretty_time = format!("{:?}", duration);
 
-                                    if let Err(e) = external_sender.send(content.to_string()).await
-                                    {
-                                        error!("Failed to send content to external sender: {}", e);
+                                    // Ensure the duration is at least 1 second
+                                    if duration < std::time::Duration::new(1, 0) {
+                                        duration = std::time::Duration::new(1, 0);
                                     }
+
+                                    debug!(
+                                         "\n--\nIndex {} ID {}\nObject {} by Model {} User {}\nCreated on {} Finish reason: {}\n {}/{}/{} Tokens/Prompt/Response {} Bytes at {}tps @ {}s.\n--\n",
+                                         choice.index,
+                                         id,
+                                         object,
+                                         model,
+                                         role,
+                                         created_date,
+                                         reason,
+                                         token_count + prompt_token_count,
+                                         prompt_token_count,
+                                         token_count,
+                                         byte_count,
+                                         token_count as u64 / duration.as_secs(),
+                                         pretty_time
+                                     );
                                 }
                             } else {
                                 error!("No choices available.");
@@ -484,6 +821,7 @@ pub async fn stream_completion(
                         }
                         Err(e) => {
                             // Handle the parse error here
+                            parse_errors += 1;
                             if debug_inline {
                                 error!("\nFailed to parse response: {}\n", e);
                                 error!("\nResponse that failed to parse: '{}'\n", response_json);
@@ -502,6 +840,14 @@ pub async fn stream_completion(
                     }
                 }
             }
+            let completion = Completion {
+                tool_calls,
+                usage,
+                content_chunks: token_count,
+                time_to_first_token,
+                finish_reason,
+            };
+            (completion, parse_errors)
         });
 
         // collect answers from the worker
@@ -515,10 +861,27 @@ pub async fn stream_completion(
             errors // Return collected errors from the task
         });
 
-        // Main task to send chunks to the worker
-        while let Ok(Some(chunk)) = response.chunk().await {
-            if let Err(e) = tx.send(chunk).await {
-                error!("Failed to send chunk: {}", e);
+        // Main task to send chunks to the worker, a stalled stream times out
+        let mut stream_error = None;
+        loop {
+            match tokio::time::timeout(options.read_timeout, response.chunk()).await {
+                Ok(Ok(Some(chunk))) => {
+                    if let Err(e) = tx.send(chunk).await {
+                        error!("Failed to send chunk: {}", e);
+                    }
+                }
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => {
+                    stream_error = Some(OpenAIError::Network(e.to_string()));
+                    break;
+                }
+                Err(_) => {
+                    stream_error = Some(OpenAIError::Timeout(format!(
+                        "no streamed data for {:?}",
+                        options.read_timeout
+                    )));
+                    break;
+                }
             }
         }
 
@@ -526,9 +889,13 @@ pub async fn stream_completion(
         drop(tx);
 
         // Await the worker task to finish processing
-        if let Err(e) = worker.await {
-            error!("Worker task failed: {}", e);
-        }
+        let (completion, parse_errors) = match worker.await {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Worker task failed: {}", e);
+                (Completion::default(), 0)
+            }
+        };
 
         // Await the error collector task to retrieve the collected errors
         let errors = match error_collector.await {
@@ -546,5 +913,16 @@ pub async fn stream_completion(
                 println!("{}", error);
             }
         }
+
+        if let Some(e) = stream_error {
+            return Err(e);
+        }
+        if completion.content_chunks == 0 && completion.tool_calls.is_empty() && parse_errors > 0 {
+            return Err(OpenAIError::Parse(format!(
+                "none of the {} streamed events could be parsed",
+                parse_errors
+            )));
+        }
+        Ok(completion)
     }
 }
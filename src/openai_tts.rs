@@ -26,7 +26,7 @@
 ///
 use bytes::Bytes;
 use reqwest::Client;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 const ENDPOINT: &str = "https://api.openai.com/v1/audio/speech";
 use crate::ApiError;
 use log::debug;
@@ -65,7 +65,7 @@ pub enum ResponseFormat {
     #[serde(rename = "flac")]
     Flac,
 }
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub enum Voice {
     #[serde(rename = "alloy")]
     Alloy,
@@ -116,7 +116,7 @@ pub async fn process_speech(data: MessageData) -> Vec<u8> {
             // Candle TTS request
             #[cfg(feature = "metavoice")]
             {
-                match metavoice(input).await {
+                match metavoice(input, data.args.metavoice_model_dir.clone()).await {
                     Ok(bytes) => return bytes.to_vec(),
                     Err(e) => {
                         eprintln!("Metavoice TTS error: {}", e);
@@ -0,0 +1,476 @@
+/*
+ * quantized_gemma.rs
+ * ------------------
+ * Gemma with quantized weights loaded from a GGUF file, the same layers as
+ * candle's gemma model using the quantized linear layers like quantized_mistral.
+ * Both GGUF layouts load, llama.cpp files (token_embd, blk.N.attn_q, ...) with
+ * the model settings in their metadata, and files keeping the safetensors tensor
+ * names as written by candle tensor-tools:
+ *   tensor-tools quantize --quantization q4k model-*.safetensors --out-file model-q4k.gguf
+*/
+
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::Activation;
+use candle_transformers::quantized_nn::{linear_b, linear_no_bias, Embedding, Linear};
+pub use candle_transformers::quantized_var_builder::VarBuilder;
+use std::path::Path;
+use std::sync::Arc;
+
+pub use candle_transformers::models::gemma::Config;
+
+// Tensor names of the two GGUF layouts
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Layout {
+    Candle,
+    LlamaCpp,
+}
+
+impl Layout {
+    fn name(&self, candle: &'static str, llama_cpp: &'static str) -> &'static str {
+        match self {
+            Layout::Candle => candle,
+            Layout::LlamaCpp => llama_cpp,
+        }
+    }
+
+    // llama.cpp has the attention and mlp tensors directly in the block
+    fn group(&self, vb: &VarBuilder, candle: &'static str) -> VarBuilder {
+        match self {
+            Layout::Candle => vb.pp(candle),
+            Layout::LlamaCpp => vb.clone(),
+        }
+    }
+}
+
+/// Model settings from the metadata of a llama.cpp GGUF, for files without a config.json.
+pub fn config_from_gguf(path: &Path) -> Result<Config> {
+    let mut file = std::fs::File::open(path)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(path))?;
+    let get = |key: &str| match content.metadata.get(key) {
+        Some(value) => Ok(value),
+        None => Err(candle_core::Error::Msg(format!(
+            "{} is not in {}",
+            key,
+            path.display()
+        ))),
+    };
+    let hidden_size = get("gemma.embedding_length")?.to_u32()? as usize;
+    let num_attention_heads = get("gemma.attention.head_count")?.to_u32()? as usize;
+    let head_dim = match get("gemma.attention.key_length") {
+        Ok(value) => value.to_u32()? as usize,
+        Err(_) => hidden_size / num_attention_heads,
+    };
+    let rope_theta = match get("gemma.rope.freq_base") {
+        Ok(value) => value.to_f32()? as f64,
+        Err(_) => 10000.0,
+    };
+    let vocab_size = match content.tensor_infos.get("token_embd.weight") {
+        Some(info) => info.shape.dims()[0],
+        None => candle_core::bail!("token_embd.weight is not in {}", path.display()),
+    };
+    let config = serde_json::json!({
+        "attention_bias": false,
+        "head_dim": head_dim,
+        "hidden_act": "gelu_pytorch_tanh",
+        "hidden_size": hidden_size,
+        "intermediate_size": get("gemma.feed_forward_length")?.to_u32()?,
+        "num_attention_heads": num_attention_heads,
+        "num_hidden_layers": get("gemma.block_count")?.to_u32()?,
+        "num_key_value_heads": get("gemma.attention.head_count_kv")?.to_u32()?,
+        "rms_norm_eps": get("gemma.attention.layer_norm_rms_epsilon")?.to_f32()? as f64,
+        "rope_theta": rope_theta,
+        "vocab_size": vocab_size,
+        "max_position_embeddings": get("gemma.context_length")?.to_u32()?,
+    });
+    serde_json::from_value(config).map_err(|e| candle_core::Error::Msg(e.to_string()))
+}
+
+// Gemma scales the normalized activations by (1 + weight)
+#[derive(Debug, Clone)]
+struct RmsNorm {
+    weight: Tensor,
+    eps: f64,
+}
+
+impl RmsNorm {
+    fn new(dim: usize, eps: f64, layout: Layout, vb: VarBuilder) -> Result<Self> {
+        let weight = vb.get(dim, "weight")?.dequantize(vb.device())?;
+        // the llama.cpp converter stores the weights with the 1 already added
+        let weight = match layout {
+            Layout::Candle => (weight + 1.0)?,
+            Layout::LlamaCpp => weight,
+        };
+        Ok(Self { weight, eps })
+    }
+}
+
+impl Module for RmsNorm {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let x_dtype = x.dtype();
+        let hidden_size = x.dim(D::Minus1)?;
+        let x = x.to_dtype(DType::F32)?;
+        let norm_x = (x.sqr()?.sum_keepdim(D::Minus1)? / hidden_size as f64)?;
+        let x_normed = x.broadcast_div(&(norm_x + self.eps)?.sqrt()?)?;
+        x_normed.to_dtype(x_dtype)?.broadcast_mul(&self.weight)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(cfg: &Config, dev: &Device) -> Result<Self> {
+        let dim = cfg.head_dim;
+        let max_seq_len = cfg.max_position_embeddings;
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?,
+            cos: freqs.cos()?,
+        })
+    }
+
+    fn apply_rotary_emb_qkv(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let q_embed = candle_nn::rotary_emb::rope(&q.contiguous()?, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope(&k.contiguous()?, &cos, &sin)?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, layout: Layout, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        let gate_proj = linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            vb.pp(layout.name("gate_proj", "ffn_gate")),
+        )?;
+        let up_proj = linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            vb.pp(layout.name("up_proj", "ffn_up")),
+        )?;
+        let down_proj = linear_no_bias(
+            intermediate_sz,
+            hidden_sz,
+            vb.pp(layout.name("down_proj", "ffn_down")),
+        )?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            // all gemma configs use the tanh approximated gelu
+            act_fn: Activation::GeluPytorchTanh,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        layout: Layout,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let num_kv_groups = num_heads / num_kv_heads;
+        let head_dim = cfg.head_dim;
+        let bias = cfg.attention_bias;
+        let q_proj = linear_b(
+            hidden_sz,
+            num_heads * head_dim,
+            bias,
+            vb.pp(layout.name("q_proj", "attn_q")),
+        )?;
+        let k_proj = linear_b(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            bias,
+            vb.pp(layout.name("k_proj", "attn_k")),
+        )?;
+        let v_proj = linear_b(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            bias,
+            vb.pp(layout.name("v_proj", "attn_v")),
+        )?;
+        let o_proj = linear_b(
+            num_heads * head_dim,
+            hidden_sz,
+            bias,
+            vb.pp(layout.name("o_proj", "attn_output")),
+        )?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            num_kv_groups,
+            head_dim,
+            rotary_emb,
+            kv_cache: None,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let value_states = value_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let (query_states, key_states) =
+            self.rotary_emb
+                .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?;
+
+        let (key_states, value_states) = match &self.kv_cache {
+            None => (key_states, value_states),
+            Some((prev_k, prev_v)) => {
+                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
+                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
+                (key_states, value_states)
+            }
+        };
+        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+
+        let key_states =
+            candle_transformers::utils::repeat_kv(key_states, self.num_kv_groups)?.contiguous()?;
+        let value_states = candle_transformers::utils::repeat_kv(value_states, self.num_kv_groups)?
+            .contiguous()?;
+
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
+        let attn_weights = match attention_mask {
+            None => attn_weights,
+            Some(mask) => attn_weights.broadcast_add(mask)?,
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        attn_weights
+            .matmul(&value_states)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, ()))?
+            .apply(&self.o_proj)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MLP,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        layout: Layout,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(rotary_emb, cfg, layout, layout.group(&vb, "self_attn"))?;
+        let mlp = MLP::new(cfg, layout, layout.group(&vb, "mlp"))?;
+        let input_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            layout,
+            vb.pp(layout.name("input_layernorm", "attn_norm")),
+        )?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            layout,
+            vb.pp(layout.name("post_attention_layernorm", "ffn_norm")),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        residual + xs
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.clear_kv_cache()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    device: Device,
+    hidden_size: usize,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let layout = if vb.contains_key("token_embd.weight") {
+            Layout::LlamaCpp
+        } else {
+            Layout::Candle
+        };
+        let vb_m = layout.group(&vb, "model");
+        let embed_name = layout.name("embed_tokens", "token_embd");
+        let embed_tokens = Embedding::new(cfg.vocab_size, cfg.hidden_size, vb_m.pp(embed_name))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(cfg, vb_m.device())?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp(layout.name("layers", "blk"));
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, layout, vb_l.pp(layer_idx))?;
+            layers.push(layer)
+        }
+        let norm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            layout,
+            vb_m.pp(layout.name("norm", "output_norm")),
+        )?;
+        // the output projection is tied to the quantized token embeddings
+        let lm_head = linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb_m.pp(embed_name))?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            device: vb.device().clone(),
+            hidden_size: cfg.hidden_size,
+        })
+    }
+
+    fn prepare_decoder_attention_mask(
+        &self,
+        tgt_len: usize,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
+            .to_dtype(DType::F32)
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(seq_len, seqlen_offset)?;
+            Some(mask)
+        };
+        let xs = self.embed_tokens.forward(input_ids)?;
+        let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .contiguous()?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+}
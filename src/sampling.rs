@@ -0,0 +1,98 @@
+/*
+ * sampling.rs
+ * -----------
+ * Token sampling for the local candle generation loops, temperature,
+ * top-k, top-p, min-p, repeat penalty and a fixed seed for reproducible runs.
+*/
+
+use crate::llm_backend::GenerationParams;
+use anyhow::Result;
+use candle_core::{DType, Tensor};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+
+/// Sampler for one generation request, built from the request parameters.
+pub struct Sampler {
+    logits_processor: LogitsProcessor,
+    min_p: Option<f64>,
+    temperature: f64,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+}
+
+impl Sampler {
+    pub fn new(params: &GenerationParams) -> Self {
+        let temperature = params.temperature as f64;
+        let top_p = if params.top_p > 0.0 && params.top_p < 1.0 {
+            Some(params.top_p as f64)
+        } else {
+            None
+        };
+        let top_k = if params.top_k > 0 {
+            Some(params.top_k)
+        } else {
+            None
+        };
+        let sampling = if temperature <= 0.0 {
+            Sampling::ArgMax
+        } else {
+            match (top_k, top_p) {
+                (None, None) => Sampling::All { temperature },
+                (Some(k), None) => Sampling::TopK { k, temperature },
+                (None, Some(p)) => Sampling::TopP { p, temperature },
+                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+            }
+        };
+        // seed 0 picks a random seed per request
+        let seed = if params.seed == 0 {
+            rand::random()
+        } else {
+            params.seed
+        };
+        let min_p = if params.min_p > 0.0 && params.min_p < 1.0 {
+            Some(params.min_p as f64)
+        } else {
+            None
+        };
+
+        Sampler {
+            logits_processor: LogitsProcessor::from_sampling(seed, sampling),
+            min_p,
+            temperature,
+            repeat_penalty: params.repeat_penalty,
+            repeat_last_n: params.repeat_last_n,
+        }
+    }
+
+    /// Pick the next token from the last position logits given the tokens so far.
+    pub fn sample(&mut self, logits: &Tensor, tokens: &[u32]) -> Result<u32> {
+        let logits = logits.to_dtype(DType::F32)?;
+        let logits = if self.repeat_penalty == 1. {
+            logits
+        } else {
+            let start_at = tokens.len().saturating_sub(self.repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(
+                &logits,
+                self.repeat_penalty,
+                &tokens[start_at..],
+            )?
+        };
+        let logits = match self.min_p {
+            Some(min_p) if self.temperature > 0.0 => apply_min_p(&logits, min_p, self.temperature)?,
+            _ => logits,
+        };
+        Ok(self.logits_processor.sample(&logits)?)
+    }
+}
+
+// Drop tokens whose probability is under min_p times the most likely one,
+// p / p_max = exp((logit - logit_max) / temperature)
+fn apply_min_p(logits: &Tensor, min_p: f64, temperature: f64) -> Result<Tensor> {
+    let values: Vec<f32> = logits.to_vec1()?;
+    let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let threshold = max + (temperature * min_p.ln()) as f32;
+    let filtered: Vec<f32> = values
+        .into_iter()
+        .map(|v| if v < threshold { f32::NEG_INFINITY } else { v })
+        .collect();
+    Ok(Tensor::new(filtered, logits.device())?)
+}
@@ -0,0 +1,397 @@
+/*
+ * schedule.rs
+ * -----------
+ * Programming schedule for a 24/7 channel, a rundown of segments that switch the
+ * query, system prompt, voice, image model and greeting of the running show. A
+ * segment starts at a time of day or every hour, or after the previous segment
+ * has told its number of stories. The main loop checks it at the top of each turn.
+*/
+
+use crate::args::Args;
+use crate::control::SD_MODELS;
+use anyhow::{anyhow, Result};
+use chrono::{Duration, NaiveDateTime, NaiveTime, Timelike};
+use serde::Deserialize;
+use std::path::Path;
+
+/// A segment of the show and the settings it switches to, unset ones are kept.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Segment {
+    #[serde(default)]
+    pub name: String,
+    /// "HH:MM" every day or "*:MM" every hour in local time, empty to follow the previous segment
+    #[serde(default)]
+    pub at: String,
+    /// Stories to tell before moving on, 0 to run until another segment starts
+    #[serde(default)]
+    pub stories: usize,
+    pub query: Option<String>,
+    pub system_prompt: Option<String>,
+    pub greeting: Option<String>,
+    pub mimic3_voice: Option<String>,
+    pub sd_model: Option<String>,
+    pub sd_custom_model: Option<String>,
+    pub assistant_image_prompt: Option<String>,
+    /// Start the segment without the conversation of the previous one
+    #[serde(default)]
+    pub clear_history: bool,
+}
+
+impl Segment {
+    /// Switch the settings of the show to this segment.
+    pub fn apply(&self, args: &mut Args) {
+        if let Some(query) = &self.query {
+            args.query = query.clone();
+        }
+        if let Some(system_prompt) = &self.system_prompt {
+            args.system_prompt = system_prompt.clone();
+        }
+        if let Some(greeting) = &self.greeting {
+            args.greeting = greeting.clone();
+        }
+        if let Some(voice) = &self.mimic3_voice {
+            args.mimic3_voice = voice.clone();
+        }
+        if let Some(sd_model) = &self.sd_model {
+            args.sd_model = sd_model.clone();
+        }
+        if let Some(sd_custom_model) = &self.sd_custom_model {
+            args.sd_custom_model = sd_custom_model.clone();
+        }
+        if let Some(prompt) = &self.assistant_image_prompt {
+            args.assistant_image_prompt = prompt.clone();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ScheduleDocument {
+    #[serde(default)]
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Trigger {
+    Daily(NaiveTime),
+    Hourly(u32),
+}
+
+impl Trigger {
+    fn parse(at: &str) -> Result<Self> {
+        if let Some(minute) = at.strip_prefix("*:") {
+            match minute.parse::<u32>() {
+                Ok(minute) if minute < 60 => Ok(Trigger::Hourly(minute)),
+                _ => Err(anyhow!("invalid minute in {}, expected *:MM", at)),
+            }
+        } else {
+            NaiveTime::parse_from_str(at, "%H:%M")
+                .map(Trigger::Daily)
+                .map_err(|_| anyhow!("invalid time {}, expected HH:MM or *:MM", at))
+        }
+    }
+
+    // Most recent time the trigger came up at or before now
+    fn last_at(&self, now: NaiveDateTime) -> NaiveDateTime {
+        match *self {
+            Trigger::Daily(time) => {
+                let today = now.date().and_time(time);
+                if today <= now {
+                    today
+                } else {
+                    today - Duration::days(1)
+                }
+            }
+            Trigger::Hourly(minute) => {
+                let this_hour = now
+                    .date()
+                    .and_hms_opt(now.hour(), minute, 0)
+                    .expect("minute is below 60");
+                if this_hour <= now {
+                    this_hour
+                } else {
+                    this_hour - Duration::hours(1)
+                }
+            }
+        }
+    }
+}
+
+/// The rundown and the segment on air.
+pub struct Schedule {
+    segments: Vec<Segment>,
+    triggers: Vec<Option<Trigger>>,
+    current: Option<usize>,
+    /// Segment a timed segment with a story count interrupted, resumed when it ends,
+    /// and whether it was on air already
+    interrupted: Option<(usize, bool)>,
+    stories: usize,
+    last_check: Option<NaiveDateTime>,
+}
+
+impl Schedule {
+    /// Read a TOML or YAML schedule of [[segments]].
+    pub fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let document: ScheduleDocument =
+            match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+                Some("yaml") | Some("yml") => serde_yaml::from_str(&text)?,
+                Some("toml") => toml::from_str(&text)?,
+                _ => return Err(anyhow!("schedule {} must be .toml, .yaml or .yml", path)),
+            };
+        Schedule::new(document.segments)
+    }
+
+    pub fn new(mut segments: Vec<Segment>) -> Result<Self> {
+        if segments.is_empty() {
+            return Err(anyhow!("the schedule has no segments"));
+        }
+        let mut triggers = Vec::new();
+        for (index, segment) in segments.iter_mut().enumerate() {
+            if segment.name.is_empty() {
+                segment.name = format!("segment {}", index + 1);
+            }
+            if let Some(sd_model) = &segment.sd_model {
+                if !SD_MODELS.contains(&sd_model.as_str()) {
+                    return Err(anyhow!(
+                        "{}: sd_model must be one of {}",
+                        segment.name,
+                        SD_MODELS.join(", ")
+                    ));
+                }
+            }
+            if segment.at.is_empty() {
+                triggers.push(None);
+            } else {
+                let trigger =
+                    Trigger::parse(&segment.at).map_err(|e| anyhow!("{}: {}", segment.name, e))?;
+                triggers.push(Some(trigger));
+            }
+        }
+        Ok(Schedule {
+            segments,
+            triggers,
+            current: None,
+            interrupted: None,
+            stories: 0,
+            last_check: None,
+        })
+    }
+
+    /// Name of the segment on air, empty before the first turn.
+    pub fn current_name(&self) -> &str {
+        self.current
+            .map(|index| self.segments[index].name.as_str())
+            .unwrap_or("")
+    }
+
+    /// Count a finished story of the segment on air.
+    pub fn story_done(&mut self) {
+        self.stories += 1;
+    }
+
+    /// The segment to switch to at `now` in local time, None to stay on the current one.
+    pub fn next_segment(&mut self, now: NaiveDateTime) -> Option<Segment> {
+        let mut resumed = false;
+        let next = match self.last_check.replace(now) {
+            // start on the segment that would be on air by the clock, else the top of the rundown
+            None => Some(
+                self.latest_trigger(NaiveDateTime::MIN, now, true)
+                    .unwrap_or(0),
+            ),
+            Some(previous) => match self.latest_trigger(previous, now, false) {
+                Some(index) => {
+                    if self.segments[index].stories == 0 {
+                        self.interrupted = None;
+                    } else if let Some(standing) = self.latest_trigger(previous, now, true) {
+                        // a segment due at the same time goes on air after the interruption
+                        self.interrupted = Some((standing, false));
+                    } else if self.interrupted.is_none() && self.current != Some(index) {
+                        self.interrupted = self.current.map(|current| (current, true));
+                    }
+                    Some(index)
+                }
+                None => self.current.and_then(|current| {
+                    let stories = self.segments[current].stories;
+                    if stories > 0 && self.stories >= stories {
+                        let (next, on_air) = self
+                            .interrupted
+                            .take()
+                            .unwrap_or(((current + 1) % self.segments.len(), false));
+                        resumed = on_air;
+                        Some(next)
+                    } else {
+                        None
+                    }
+                }),
+            },
+        }?;
+        self.current = Some(next);
+        self.stories = 0;
+        let mut segment = self.segments[next].clone();
+        // back from an interruption without greeting again
+        if resumed {
+            segment.greeting = None;
+        }
+        Some(segment)
+    }
+
+    // Segment whose trigger came up last after `since`, only open ended segments when `standing`
+    fn latest_trigger(
+        &self,
+        since: NaiveDateTime,
+        now: NaiveDateTime,
+        standing: bool,
+    ) -> Option<usize> {
+        let mut latest: Option<(usize, NaiveDateTime)> = None;
+        for (index, trigger) in self.triggers.iter().enumerate() {
+            let Some(trigger) = trigger else { continue };
+            if standing && self.segments[index].stories > 0 {
+                continue;
+            }
+            let at = trigger.last_at(now);
+            // segments with a story count win a tie, they interrupt the others
+            let later = latest.map_or(true, |(latest_index, latest_at)| {
+                at > latest_at || (at == latest_at && self.segments[latest_index].stories == 0)
+            });
+            if at > since && later {
+                latest = Some((index, at));
+            }
+        }
+        latest.map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn segment(name: &str, at: &str, stories: usize) -> Segment {
+        Segment {
+            name: name.to_string(),
+            at: at.to_string(),
+            stories,
+            greeting: Some(format!("Welcome to {}", name)),
+            ..Default::default()
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 2, 20)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn name(segment: Option<Segment>) -> Option<String> {
+        segment.map(|segment| segment.name)
+    }
+
+    #[test]
+    fn starts_mid_day_on_the_segment_on_air() {
+        let segments = vec![
+            segment("morning", "06:00", 0),
+            segment("evening", "18:00", 0),
+        ];
+        let mut schedule = Schedule::new(segments.clone()).unwrap();
+        assert_eq!(
+            name(schedule.next_segment(at(12, 30))).as_deref(),
+            Some("morning")
+        );
+        assert_eq!(schedule.current_name(), "morning");
+        assert!(schedule.next_segment(at(12, 35)).is_none());
+        assert_eq!(
+            name(schedule.next_segment(at(18, 0))).as_deref(),
+            Some("evening")
+        );
+
+        // before the first segment of the day the one from last night is on air
+        let mut schedule = Schedule::new(segments).unwrap();
+        assert_eq!(
+            name(schedule.next_segment(at(3, 0))).as_deref(),
+            Some("evening")
+        );
+    }
+
+    #[test]
+    fn untimed_segments_follow_by_story_count() {
+        let mut schedule =
+            Schedule::new(vec![segment("one", "", 1), segment("two", "", 2)]).unwrap();
+        assert_eq!(
+            name(schedule.next_segment(at(9, 0))).as_deref(),
+            Some("one")
+        );
+        assert!(schedule.next_segment(at(9, 1)).is_none());
+        schedule.story_done();
+        assert_eq!(
+            name(schedule.next_segment(at(9, 2))).as_deref(),
+            Some("two")
+        );
+        schedule.story_done();
+        assert!(schedule.next_segment(at(9, 3)).is_none());
+        schedule.story_done();
+        assert_eq!(
+            name(schedule.next_segment(at(9, 4))).as_deref(),
+            Some("one")
+        );
+    }
+
+    #[test]
+    fn hourly_news_interrupts_and_resumes_without_greeting() {
+        let mut schedule = Schedule::new(vec![
+            segment("show", "06:00", 0),
+            segment("news", "*:00", 2),
+        ])
+        .unwrap();
+        // the news isn't joined halfway through at startup
+        assert_eq!(
+            name(schedule.next_segment(at(9, 30))).as_deref(),
+            Some("show")
+        );
+        assert!(schedule.next_segment(at(9, 45)).is_none());
+
+        let news = schedule.next_segment(at(10, 0)).unwrap();
+        assert_eq!(news.name, "news");
+        assert_eq!(news.greeting.as_deref(), Some("Welcome to news"));
+        schedule.story_done();
+        assert!(schedule.next_segment(at(10, 5)).is_none());
+        schedule.story_done();
+
+        let show = schedule.next_segment(at(10, 10)).unwrap();
+        assert_eq!(show.name, "show");
+        assert!(show.greeting.is_none());
+        assert!(schedule.next_segment(at(10, 30)).is_none());
+
+        // and again the next hour
+        assert_eq!(
+            name(schedule.next_segment(at(11, 0))).as_deref(),
+            Some("news")
+        );
+    }
+
+    #[test]
+    fn segments_due_at_the_same_time() {
+        let mut schedule = Schedule::new(vec![
+            segment("night", "22:00", 0),
+            segment("morning", "06:00", 0),
+            segment("news", "*:00", 1),
+        ])
+        .unwrap();
+        assert_eq!(
+            name(schedule.next_segment(at(5, 30))).as_deref(),
+            Some("night")
+        );
+
+        // the segment with a story count goes first, then the one it held back
+        assert_eq!(
+            name(schedule.next_segment(at(6, 0))).as_deref(),
+            Some("news")
+        );
+        schedule.story_done();
+        let morning = schedule.next_segment(at(6, 10)).unwrap();
+        assert_eq!(morning.name, "morning");
+        // it wasn't on air yet, so it still greets
+        assert_eq!(morning.greeting.as_deref(), Some("Welcome to morning"));
+        assert!(schedule.next_segment(at(6, 20)).is_none());
+    }
+}
@@ -0,0 +1,601 @@
+/*
+ * server.rs
+ * ---------
+ * Embedded OpenAI compatible HTTP server, other tools on the network can use
+ * the warm candle models, the configured TTS engine and Stable Diffusion.
+*/
+
+use crate::args::Args;
+#[cfg(feature = "metavoice")]
+use crate::candle_metavoice::metavoice;
+use crate::llm_backend::{candle_backend, default_chat_format, GenerationParams, LlmBackend};
+use crate::mimic3_tts::{tts as mimic3_tts, Request as Mimic3TTSRequest};
+use crate::model_files::ModelFiles;
+use crate::model_service::ModelService;
+use crate::openai_api::{ImageUrl, Message};
+use crate::openai_tts::{tts as oai_tts, Request as OAITTSRequest, Voice as OAITTSVoice};
+use crate::sd_automatic::sd_auto;
+use crate::stable_diffusion::{sd, SDConfig};
+use crate::usage::Usage;
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::engine::general_purpose;
+use base64::Engine;
+use image::{DynamicImage, ImageOutputFormat};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::io::Cursor;
+use std::sync::Arc;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+// Local models the chat endpoint can load besides the bot's own backend
+const CANDLE_MODELS: [&str; 5] = ["mistral", "gemma", "llama", "phi", "qwen"];
+// Limits of the images endpoint, larger requests tie up Stable Diffusion for minutes
+const MAX_IMAGES: usize = 4;
+const MAX_IMAGE_SIZE: usize = 2048;
+
+#[derive(Clone)]
+pub struct ServerState {
+    args: Args,
+    /// The backend the bot itself uses, shared with the chat endpoint when it is a local model
+    backend: Arc<dyn LlmBackend>,
+    /// Chat requests of server clients, apart from the model queue the bot uses
+    chat_slots: Arc<Semaphore>,
+    /// Speech and image jobs of server clients, they share the GPU with the show
+    speech_slots: Arc<Semaphore>,
+    image_slots: Arc<Semaphore>,
+}
+
+impl ServerState {
+    pub fn new(args: Args, backend: Arc<dyn LlmBackend>) -> Self {
+        let slots = args.server_queue_size.max(1);
+        ServerState {
+            args,
+            backend,
+            chat_slots: Arc::new(Semaphore::new(slots)),
+            speech_slots: Arc::new(Semaphore::new(slots)),
+            image_slots: Arc::new(Semaphore::new(slots)),
+        }
+    }
+
+    // The bot's backend when it is a local model, the API backend holds the operator's key
+    fn local_backend(&self) -> Option<&Arc<dyn LlmBackend>> {
+        if CANDLE_MODELS.contains(&self.backend.name()) {
+            Some(&self.backend)
+        } else {
+            None
+        }
+    }
+
+    // The bot's local backend, or a local candle model by name, reusing a model the bot or
+    // twitch chat already loaded
+    fn backend(&self, model: &str) -> Result<Arc<dyn LlmBackend>, ServerError> {
+        if let Some(backend) = self.local_backend() {
+            if model.is_empty() || model == backend.name() {
+                return Ok(backend.clone());
+            }
+        } else if model.is_empty() {
+            return Err(ServerError::bad_request(&format!(
+                "model is required, one of {}",
+                CANDLE_MODELS.join(", ")
+            )));
+        }
+        if !CANDLE_MODELS.contains(&model) {
+            return Err(ServerError::new(
+                StatusCode::NOT_FOUND,
+                "model_not_found",
+                &format!("The model {} does not exist", model),
+            ));
+        }
+        let config = ModelService::running(model);
+        let (model_id, quantized, model_files) = match config {
+            Some(config) => (config.model_id, config.quantized, config.model_files),
+            None => ("auto".to_string(), false, ModelFiles::default()),
+        };
+        candle_backend(
+            model,
+            &model_id,
+            quantized,
+            model_files,
+            default_chat_format(model),
+            self.args.model_queue_size,
+        )
+        .map_err(|e| ServerError::internal(&e.to_string()))
+    }
+}
+
+/// Error answered with an OpenAI style error body.
+pub struct ServerError {
+    status: StatusCode,
+    error_type: String,
+    message: String,
+}
+
+impl ServerError {
+    fn new(status: StatusCode, error_type: &str, message: &str) -> Self {
+        ServerError {
+            status,
+            error_type: error_type.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn bad_request(message: &str) -> Self {
+        ServerError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+    }
+
+    fn internal(message: &str) -> Self {
+        ServerError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", message)
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        let body = json!({"error": {"message": self.message, "type": self.error_type}});
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct ChatRequest {
+    #[serde(default)]
+    model: String,
+    messages: Vec<ChatRequestMessage>,
+    max_tokens: Option<usize>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    seed: Option<u64>,
+    presence_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    /// A string or a list of strings
+    stop: Option<Value>,
+    #[serde(default)]
+    stream: bool,
+    stream_options: Option<StreamOptions>,
+}
+
+#[derive(Deserialize)]
+struct StreamOptions {
+    #[serde(default)]
+    include_usage: bool,
+}
+
+#[derive(Deserialize)]
+struct ChatRequestMessage {
+    role: String,
+    /// Text or an array of text and image_url content parts
+    #[serde(default)]
+    content: Value,
+}
+
+impl ChatRequestMessage {
+    fn to_message(&self) -> Message {
+        let mut message = Message {
+            role: self.role.clone(),
+            ..Default::default()
+        };
+        match &self.content {
+            Value::String(text) => message.content = text.clone(),
+            Value::Array(parts) => {
+                let mut texts = Vec::new();
+                for part in parts {
+                    match part.get("type").and_then(|t| t.as_str()) {
+                        Some("text") => texts.push(part["text"].as_str().unwrap_or("").to_string()),
+                        Some("image_url") => {
+                            if let Some(url) = part["image_url"]["url"].as_str() {
+                                message.images.push(ImageUrl::new(url));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                message.content = texts.join("\n");
+            }
+            _ => {}
+        }
+        message
+    }
+}
+
+// A slot for the request, held until it is answered, or a 429 when all are taken
+fn acquire_slot(slots: &Arc<Semaphore>, kind: &str) -> Result<OwnedSemaphorePermit, ServerError> {
+    slots.clone().try_acquire_owned().map_err(|_| {
+        ServerError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limit_exceeded",
+            &format!("Too many {} requests, try again later", kind),
+        )
+    })
+}
+
+fn check_api_key(args: &Args, headers: &HeaderMap) -> Result<(), ServerError> {
+    if args.server_api_key.is_empty() {
+        return Ok(());
+    }
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    if authorization == format!("Bearer {}", args.server_api_key) {
+        Ok(())
+    } else {
+        Err(ServerError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_api_key",
+            "Invalid API key",
+        ))
+    }
+}
+
+fn chunk_event(
+    id: &str,
+    created: i64,
+    model: &str,
+    delta: Value,
+    finish_reason: Option<&str>,
+) -> Event {
+    Event::default().data(
+        json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        })
+        .to_string(),
+    )
+}
+
+fn usage_json(usage: &Usage) -> Value {
+    json!({
+        "prompt_tokens": usage.prompt_tokens,
+        "completion_tokens": usage.completion_tokens,
+        "total_tokens": usage.prompt_tokens + usage.completion_tokens,
+    })
+}
+
+// An answer as long as the token limit was most likely cut off by it
+fn finish_reason(usage: Option<&Usage>, max_tokens: usize) -> &'static str {
+    match usage {
+        Some(usage) if usage.completion_tokens >= max_tokens => "length",
+        _ => "stop",
+    }
+}
+
+async fn chat_completions(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(request): Json<ChatRequest>,
+) -> Result<Response, ServerError> {
+    check_api_key(&state.args, &headers)?;
+    if request.messages.is_empty() {
+        return Err(ServerError::bad_request("messages must not be empty"));
+    }
+    // held until the answer is done, streamed answers hand it to the streaming task
+    let permit = acquire_slot(&state.chat_slots, "chat")?;
+    let backend = state.backend(&request.model)?;
+    let model = if request.model.is_empty() {
+        backend.name().to_string()
+    } else {
+        request.model.clone()
+    };
+
+    let mut params = GenerationParams::from_args(
+        &state.args,
+        request.max_tokens.unwrap_or(state.args.max_tokens as usize),
+    );
+    params.temperature = request.temperature.unwrap_or(params.temperature);
+    params.top_p = request.top_p.unwrap_or(params.top_p);
+    params.seed = request.seed.unwrap_or(params.seed);
+    params.presence_penalty = request.presence_penalty.unwrap_or(params.presence_penalty);
+    params.frequency_penalty = request
+        .frequency_penalty
+        .unwrap_or(params.frequency_penalty);
+    match &request.stop {
+        // a string stop is one literal sequence, commas and all
+        Some(Value::String(stop)) => params.stop = vec![stop.clone()],
+        Some(Value::Array(stops)) => {
+            params.stop = stops
+                .iter()
+                .filter_map(|stop| stop.as_str().map(|s| s.to_string()))
+                .collect()
+        }
+        _ => {}
+    }
+    let max_tokens = params.max_tokens;
+    let messages: Vec<Message> = request.messages.iter().map(|m| m.to_message()).collect();
+
+    let id = format!("chatcmpl-{}", Uuid::new_v4().simple());
+    let created = chrono::Utc::now().timestamp();
+    info!(
+        "Server chat completion {} with {} for {} messages.",
+        id,
+        model,
+        messages.len()
+    );
+
+    let (sender, mut receiver) = mpsc::channel::<String>(32768);
+    let generation = backend.generate(messages, params, sender);
+
+    if !request.stream {
+        let mut content = String::new();
+        while let Some(token) = receiver.recv().await {
+            content.push_str(&token);
+        }
+        let usage = match generation.await {
+            Ok(Ok(usage)) => usage,
+            Ok(Err(e)) => return Err(ServerError::internal(&e.to_string())),
+            Err(e) => return Err(ServerError::internal(&e.to_string())),
+        };
+        drop(permit);
+        return Ok(Json(json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": created,
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": content},
+                "finish_reason": finish_reason(Some(&usage), max_tokens),
+            }],
+            "usage": usage_json(&usage),
+        }))
+        .into_response());
+    }
+
+    let include_usage = request
+        .stream_options
+        .map_or(false, |options| options.include_usage);
+    let (event_sender, event_receiver) = mpsc::channel::<Event>(32);
+    tokio::spawn(async move {
+        let _permit = permit;
+        let mut delta = json!({"role": "assistant", "content": ""});
+        while let Some(token) = receiver.recv().await {
+            delta["content"] = Value::String(token);
+            if event_sender
+                .send(chunk_event(&id, created, &model, delta, None))
+                .await
+                .is_err()
+            {
+                // the client went away
+                return;
+            }
+            delta = json!({"content": ""});
+        }
+        let result = match generation.await {
+            Ok(Ok(usage)) => Ok(usage),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let usage = match result {
+            Ok(usage) => usage,
+            Err(e) => {
+                // an error event instead of the finish chunk, so it doesn't look complete
+                error!("Server chat completion {} failed: {}", id, e);
+                let body = json!({"error": {"message": e, "type": "server_error"}});
+                let _ = event_sender
+                    .send(Event::default().data(body.to_string()))
+                    .await;
+                return;
+            }
+        };
+        let reason = finish_reason(Some(&usage), max_tokens);
+        let _ = event_sender
+            .send(chunk_event(&id, created, &model, json!({}), Some(reason)))
+            .await;
+        if include_usage {
+            let usage_chunk = json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": model,
+                "choices": [],
+                "usage": usage_json(&usage),
+            });
+            let _ = event_sender
+                .send(Event::default().data(usage_chunk.to_string()))
+                .await;
+        }
+        let _ = event_sender.send(Event::default().data("[DONE]")).await;
+    });
+
+    let stream = futures::stream::unfold(event_receiver, |mut receiver| async move {
+        receiver
+            .recv()
+            .await
+            .map(|event| (Ok::<Event, Infallible>(event), receiver))
+    });
+    Ok(Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct SpeechRequest {
+    input: String,
+    voice: Option<String>,
+}
+
+async fn audio_speech(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(request): Json<SpeechRequest>,
+) -> Result<Response, ServerError> {
+    check_api_key(&state.args, &headers)?;
+    let _permit = acquire_slot(&state.speech_slots, "speech")?;
+    let args = &state.args;
+    info!("Server speech for {} characters.", request.input.len());
+    let (audio, content_type) = if args.oai_tts {
+        let voice = match &request.voice {
+            Some(voice) => serde_json::from_value(json!(voice))
+                .map_err(|_| ServerError::bad_request(&format!("unknown voice {}", voice)))?,
+            None => OAITTSVoice::Nova,
+        };
+        let openai_key = std::env::var("OPENAI_API_KEY")
+            .map_err(|_| ServerError::internal("OPENAI_API_KEY not found"))?;
+        let oai_request = OAITTSRequest::new("tts-1".to_string(), request.input, voice);
+        let audio = oai_tts(oai_request, &openai_key)
+            .await
+            .map_err(|e| ServerError::internal(&e.to_string()))?;
+        (audio.to_vec(), "audio/mpeg")
+    } else if args.mimic3_tts || args.tts_enable {
+        let voice = request.voice.unwrap_or_else(|| args.mimic3_voice.clone());
+        let audio = mimic3_tts(Mimic3TTSRequest::new(request.input, voice))
+            .await
+            .map_err(|e| ServerError::internal(&e.to_string()))?;
+        (audio.to_vec(), "audio/wav")
+    } else if args.metavoice_tts {
+        let audio = metavoice_speech(request.input, args.metavoice_model_dir.clone()).await?;
+        (audio, "audio/wav")
+    } else {
+        return Err(ServerError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "invalid_request_error",
+            "No TTS engine is configured",
+        ));
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], audio).into_response())
+}
+
+#[cfg(feature = "metavoice")]
+async fn metavoice_speech(input: String, model_dir: String) -> Result<Vec<u8>, ServerError> {
+    match metavoice(input, model_dir).await {
+        Ok(audio) => Ok(audio.to_vec()),
+        Err(e) => Err(ServerError::internal(&e.to_string())),
+    }
+}
+
+#[cfg(not(feature = "metavoice"))]
+async fn metavoice_speech(_input: String, _model_dir: String) -> Result<Vec<u8>, ServerError> {
+    Err(ServerError::new(
+        StatusCode::NOT_IMPLEMENTED,
+        "invalid_request_error",
+        "Metavoice feature not enabled",
+    ))
+}
+
+#[derive(Deserialize)]
+struct ImageRequest {
+    prompt: String,
+    n: Option<usize>,
+    /// "512x512" style width by height
+    size: Option<String>,
+    response_format: Option<String>,
+}
+
+async fn images_generations(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(request): Json<ImageRequest>,
+) -> Result<Response, ServerError> {
+    check_api_key(&state.args, &headers)?;
+    let args = &state.args;
+    if request.response_format.as_deref().unwrap_or("b64_json") != "b64_json" {
+        return Err(ServerError::bad_request(
+            "only the b64_json response_format is supported",
+        ));
+    }
+    let (width, height) = match &request.size {
+        Some(size) => match size.split_once('x') {
+            Some((width, height)) => (
+                width
+                    .parse::<usize>()
+                    .map_err(|_| ServerError::bad_request("invalid size"))?,
+                height
+                    .parse::<usize>()
+                    .map_err(|_| ServerError::bad_request("invalid size"))?,
+            ),
+            None => return Err(ServerError::bad_request("size must be WIDTHxHEIGHT")),
+        },
+        None => (args.sd_width, args.sd_height),
+    };
+    if !(64..=MAX_IMAGE_SIZE).contains(&width) || !(64..=MAX_IMAGE_SIZE).contains(&height) {
+        return Err(ServerError::bad_request(&format!(
+            "size must be between 64x64 and {}x{}",
+            MAX_IMAGE_SIZE, MAX_IMAGE_SIZE
+        )));
+    }
+    let n = request.n.unwrap_or(1);
+    if n == 0 || n > MAX_IMAGES {
+        return Err(ServerError::bad_request(&format!(
+            "n must be between 1 and {}",
+            MAX_IMAGES
+        )));
+    }
+
+    let _permit = acquire_slot(&state.image_slots, "image")?;
+    let mut sd_config = SDConfig::from_args(args, request.prompt);
+    sd_config.width = Some(width);
+    sd_config.height = Some(height);
+    sd_config.num_samples = n;
+    // the requested size as is, without the show's intermediary images or placement
+    sd_config.intermediary_images = false;
+    sd_config.image_position = None;
+    sd_config.scaled_width = None;
+    sd_config.scaled_height = None;
+    info!(
+        "Server image generation of {} {}x{} images.",
+        sd_config.num_samples, width, height
+    );
+
+    let images = if args.sd_api {
+        sd_auto(sd_config).await
+    } else {
+        sd(sd_config).await
+    }
+    .map_err(|e| ServerError::internal(&e.to_string()))?;
+
+    let mut data = Vec::new();
+    for image in images {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .map_err(|e| ServerError::internal(&e.to_string()))?;
+        data.push(json!({"b64_json": general_purpose::STANDARD.encode(png.into_inner())}));
+    }
+    Ok(Json(json!({"created": chrono::Utc::now().timestamp(), "data": data})).into_response())
+}
+
+async fn models(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ServerError> {
+    check_api_key(&state.args, &headers)?;
+    let mut names: Vec<String> = state
+        .local_backend()
+        .map(|backend| backend.name().to_string())
+        .into_iter()
+        .collect();
+    for model in CANDLE_MODELS {
+        if !names.iter().any(|name| name == model) {
+            names.push(model.to_string());
+        }
+    }
+    let data: Vec<Value> = names
+        .iter()
+        .map(|name| json!({"id": name, "object": "model", "owned_by": "rsllm"}))
+        .collect();
+    Ok(Json(json!({"object": "list", "data": data})))
+}
+
+/// Serve the OpenAI compatible API on `addr` until the process exits.
+pub async fn serve(addr: &str, state: ServerState) -> Result<()> {
+    let app = Router::new()
+        .route("/v1/models", get(models))
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/audio/speech", post(audio_speech))
+        .route("/v1/images/generations", post(images_generations))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("OpenAI compatible server listening on {}", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
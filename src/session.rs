@@ -0,0 +1,86 @@
+/*
+ * session.rs
+ * ----------
+ * Named sessions persisting the conversation history and show counters
+ * to disk each turn, so a restart picks the story up where it left off.
+*/
+
+use crate::openai_api::Message;
+use crate::usage::SessionUsage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Session {
+    pub name: String,
+    pub messages: Vec<Message>,
+    pub iterations: i32,
+    pub paragraph_count: usize,
+    pub updated: String,
+    /// LLM usage per backend and model over the session
+    #[serde(default)]
+    pub usage: SessionUsage,
+}
+
+impl Session {
+    pub fn new(name: &str) -> Self {
+        Session {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Session file for `name` in the session directory.
+    pub fn path(dir: &str, name: &str) -> PathBuf {
+        Path::new(dir).join(format!("{}.json", name))
+    }
+
+    /// Load a saved session, None if it hasn't been saved yet.
+    pub fn load(dir: &str, name: &str) -> Result<Option<Session>> {
+        let path = Session::path(dir, name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let session: Session = serde_json::from_reader(std::fs::File::open(&path)?)?;
+        Ok(Some(session))
+    }
+
+    /// Save the session, written to a temporary file first so a crash can't leave it half written.
+    pub fn save(&mut self, dir: &str) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        self.updated = chrono::Local::now().to_rfc3339();
+        let path = Session::path(dir, &self.name);
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Transcript of the conversation as Markdown.
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!(
+            "# Session {}\n\nIterations: {}, paragraphs: {}, updated: {}\n",
+            self.name, self.iterations, self.paragraph_count, self.updated
+        );
+        for message in &self.messages {
+            markdown += &format!("\n## {}\n\n{}\n", message.role, message.content);
+        }
+        markdown
+    }
+
+    /// Write a transcript, Markdown for a .md file and JSON otherwise.
+    pub fn export(&self, path: &str) -> Result<()> {
+        let transcript = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("md") | Some("markdown") => self.to_markdown(),
+            _ => serde_json::to_string_pretty(self)?,
+        };
+        if let Some(parent) = Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        std::fs::write(path, transcript)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+/*
+ * sse.rs
+ * ------
+ * Incremental server-sent events decoder for streamed completions, network
+ * chunks are buffered as bytes until a whole event has arrived so events
+ * and multi-byte UTF-8 characters split across chunks are kept intact.
+*/
+
+/// One server-sent event, the data lines joined with newlines.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    pub data: String,
+}
+
+impl SseEvent {
+    /// The OpenAI end of stream marker.
+    pub fn is_done(&self) -> bool {
+        self.data.trim() == "[DONE]"
+    }
+}
+
+#[derive(Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    /// Bytes of the buffer already searched for the end of an event
+    scanned: usize,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        SseDecoder::default()
+    }
+
+    /// Add a network chunk and return the events completed by it.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        // a separator may have started in the last bytes of the previous chunk
+        let mut start = self.scanned.saturating_sub(MAX_SEPARATOR_LEN - 1);
+        while let Some((end, separator_len)) = find_event_end(&self.buffer, start) {
+            let raw: Vec<u8> = self.buffer.drain(..end + separator_len).collect();
+            if let Some(event) = parse_event(&raw[..end]) {
+                events.push(event);
+            }
+            start = 0;
+        }
+        self.scanned = self.buffer.len();
+        events
+    }
+
+    /// The last event when the stream ends without a blank line after it.
+    pub fn finish(&mut self) -> Vec<SseEvent> {
+        let raw = std::mem::take(&mut self.buffer);
+        self.scanned = 0;
+        parse_event(&raw).into_iter().collect()
+    }
+}
+
+const SEPARATORS: [&[u8]; 3] = [b"\r\n\r\n", b"\n\n", b"\r\r"];
+const MAX_SEPARATOR_LEN: usize = 4;
+
+// Events end at a blank line, with \n, \r\n or \r line endings, searched from start
+fn find_event_end(buffer: &[u8], start: usize) -> Option<(usize, usize)> {
+    for index in start..buffer.len() {
+        for separator in SEPARATORS {
+            if buffer[index..].starts_with(separator) {
+                return Some((index, separator.len()));
+            }
+        }
+    }
+    None
+}
+
+fn parse_event(raw: &[u8]) -> Option<SseEvent> {
+    // whole events only hold whole characters, anything invalid is from the server
+    let text = String::from_utf8_lossy(raw);
+    let mut event = SseEvent::default();
+    let mut data_lines: Vec<&str> = Vec::new();
+    for line in text.split(['\n', '\r']) {
+        if line.is_empty() || line.starts_with(':') {
+            // blank or comment line, comments keep idle connections alive
+            continue;
+        }
+        let (field, value) = match line.find(':') {
+            Some(index) => {
+                let value = &line[index + 1..];
+                (&line[..index], value.strip_prefix(' ').unwrap_or(value))
+            }
+            None => (line, ""),
+        };
+        match field {
+            "data" => data_lines.push(value),
+            "event" => event.event = Some(value.to_string()),
+            "id" => event.id = Some(value.to_string()),
+            "retry" => {}
+            // servers that stream bare JSON lines without the data: field
+            _ if line.starts_with('{') => data_lines.push(line),
+            _ => {}
+        }
+    }
+    if data_lines.is_empty() && event.event.is_none() {
+        return None;
+    }
+    event.data = data_lines.join("\n");
+    Some(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events = Vec::new();
+        for chunk in chunks {
+            events.extend(decoder.push(chunk));
+        }
+        events.extend(decoder.finish());
+        events
+    }
+
+    fn data(events: &[SseEvent]) -> Vec<&str> {
+        events.iter().map(|event| event.data.as_str()).collect()
+    }
+
+    #[test]
+    fn events_in_one_chunk() {
+        let events = decode(&[b"data: one\n\ndata: two\n\ndata: [DONE]\n\n"]);
+        assert_eq!(data(&events), ["one", "two", "[DONE]"]);
+        assert!(events[2].is_done());
+    }
+
+    #[test]
+    fn event_split_across_chunks() {
+        let events = decode(&[b"data: {\"a\"", b": 1}", b"\n", b"\ndata: b\n\n"]);
+        assert_eq!(data(&events), ["{\"a\": 1}", "b"]);
+    }
+
+    #[test]
+    fn crlf_separator_split_across_chunks() {
+        let events = decode(&[b"data: one\r\n\r", b"\ndata: two\r", b"\n\r\n"]);
+        assert_eq!(data(&events), ["one", "two"]);
+    }
+
+    #[test]
+    fn utf8_character_split_across_chunks() {
+        let text = "data: caf\u{e9} \u{1f600}\n\n".as_bytes();
+        // split inside the two byte e-acute and inside the four byte emoji
+        let events = decode(&[&text[..10], &text[10..14], &text[14..]]);
+        assert_eq!(data(&events), ["caf\u{e9} \u{1f600}"]);
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let text = "event: delta\nid: 7\ndata: line one\ndata: line two\n\n".as_bytes();
+        let chunks: Vec<&[u8]> = text.chunks(1).collect();
+        let events = decode(&chunks);
+        assert_eq!(
+            events,
+            [SseEvent {
+                event: Some("delta".to_string()),
+                id: Some("7".to_string()),
+                data: "line one\nline two".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn comments_are_skipped_and_last_event_is_finished() {
+        let events = decode(&[b": keep-alive\n\n", b"data: tail"]);
+        assert_eq!(data(&events), ["tail"]);
+    }
+
+    #[test]
+    fn bare_json_lines() {
+        let events = decode(&[b"{\"x\": 1}\n\n"]);
+        assert_eq!(data(&events), ["{\"x\": 1}"]);
+    }
+}
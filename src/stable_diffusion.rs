@@ -5,6 +5,8 @@ extern crate accelerate_src;
 #[cfg(feature = "mkl")]
 extern crate intel_mkl_src;
 
+use crate::args::Args;
+use crate::model_files::require_files;
 use crate::scale_image;
 use crate::truncate_tokens;
 use candle_transformers::models::stable_diffusion;
@@ -95,23 +97,41 @@ impl StableDiffusionVersion {
 }
 
 impl ModelFile {
+    // Path inside a local model directory, the hub repo layout with the tokenizers alongside
+    fn local_path(&self, version: StableDiffusionVersion, use_f16: bool) -> &'static str {
+        match self {
+            Self::Tokenizer => "tokenizer/tokenizer.json",
+            Self::Tokenizer2 => "tokenizer_2/tokenizer.json",
+            Self::Clip => version.clip_file(use_f16),
+            Self::Clip2 => version.clip2_file(use_f16),
+            Self::Unet => version.unet_file(use_f16),
+            Self::Vae => version.vae_file(use_f16),
+        }
+    }
+
     fn get(
         &self,
         filename: Option<String>,
         version: StableDiffusionVersion,
         use_f16: bool,
+        model_dir: &Option<String>,
     ) -> Result<std::path::PathBuf> {
         use hf_hub::api::sync::Api;
-        match filename {
-            Some(filename) => Ok(std::path::PathBuf::from(filename)),
-            None => {
+        match (filename, model_dir) {
+            (Some(filename), _) => Ok(std::path::PathBuf::from(filename)),
+            (None, Some(model_dir)) => {
+                Ok(std::path::Path::new(model_dir).join(self.local_path(version, use_f16)))
+            }
+            (None, None) => {
                 let (repo, path) = match self {
                     Self::Tokenizer => {
                         let tokenizer_repo = match version {
                             StableDiffusionVersion::V1_5 | StableDiffusionVersion::V2_1 => {
                                 "openai/clip-vit-base-patch32"
                             }
-                            StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo | StableDiffusionVersion::Custom => {
+                            StableDiffusionVersion::Xl
+                            | StableDiffusionVersion::Turbo
+                            | StableDiffusionVersion::Custom => {
                                 // This seems similar to the patch32 version except some very small
                                 // difference in the split regex.
                                 "openai/clip-vit-large-patch14"
@@ -130,7 +150,9 @@ impl ModelFile {
                         // See https://github.com/huggingface/candle/issues/1060
                         if matches!(
                             version,
-                            StableDiffusionVersion::Xl | StableDiffusionVersion::Turbo | StableDiffusionVersion::Custom,
+                            StableDiffusionVersion::Xl
+                                | StableDiffusionVersion::Turbo
+                                | StableDiffusionVersion::Custom,
                         ) && use_f16
                         {
                             (
@@ -155,6 +177,7 @@ fn text_embeddings(
     uncond_prompt: &str,
     tokenizer: Option<String>,
     clip_weights: Option<String>,
+    model_dir: &Option<String>,
     sd_version: StableDiffusionVersion,
     sd_config: &stable_diffusion::StableDiffusionConfig,
     use_f16: bool,
@@ -168,7 +191,7 @@ fn text_embeddings(
     } else {
         ModelFile::Tokenizer2
     };
-    let tokenizer = tokenizer_file.get(tokenizer, sd_version, use_f16)?;
+    let tokenizer = tokenizer_file.get(tokenizer, sd_version, use_f16, model_dir)?;
     let tokenizer = Tokenizer::from_file(tokenizer).map_err(E::msg)?;
     let pad_id = match &sd_config.clip.pad_with {
         Some(padding) => *tokenizer.get_vocab(true).get(padding.as_str()).unwrap(),
@@ -195,7 +218,7 @@ fn text_embeddings(
     } else {
         ModelFile::Clip2
     };
-    let clip_weights = clip_weights_file.get(clip_weights, sd_version, false)?;
+    let clip_weights = clip_weights_file.get(clip_weights, sd_version, false, model_dir)?;
     let clip_config = if first {
         &sd_config.clip
     } else {
@@ -259,6 +282,7 @@ pub struct SDConfig {
     pub clip_weights: Option<String>,
     pub vae_weights: Option<String>,
     pub tokenizer: Option<String>,
+    pub model_dir: Option<String>,
     pub sliced_attention_size: Option<usize>,
     pub n_steps: Option<usize>,
     pub num_samples: usize,
@@ -290,6 +314,7 @@ impl SDConfig {
             clip_weights: None,
             vae_weights: None,
             tokenizer: None,
+            model_dir: None,
             sliced_attention_size: None,
             n_steps: None,
             num_samples: 1,
@@ -307,6 +332,38 @@ impl SDConfig {
             seed: None,
         }
     }
+
+    /// Settings of the show from the command line for an image of `prompt`.
+    pub fn from_args(args: &Args, prompt: String) -> Self {
+        let mut sd_config = SDConfig::new();
+        sd_config.prompt = prompt;
+        sd_config.height = Some(args.sd_height);
+        sd_config.width = Some(args.sd_width);
+        sd_config.image_position = Some(args.image_alignment.clone());
+        sd_config.intermediary_images = args.sd_intermediary_images;
+        sd_config.custom_model = Some(args.sd_custom_model.clone());
+        sd_config.model_dir = if args.sd_model_dir.is_empty() {
+            None
+        } else {
+            Some(args.sd_model_dir.clone())
+        };
+        if args.sd_scaled_height > 0 {
+            sd_config.scaled_height = Some(args.sd_scaled_height);
+        }
+        if args.sd_scaled_width > 0 {
+            sd_config.scaled_width = Some(args.sd_scaled_width);
+        }
+        // match args.sd_model with on of the strings "1.5", "2.1", "xl", "turbo" and set the sd_version accordingly
+        sd_config.sd_version = match args.sd_model.as_str() {
+            "2.1" => StableDiffusionVersion::V2_1,
+            "xl" => StableDiffusionVersion::Xl,
+            "turbo" => StableDiffusionVersion::Turbo,
+            "Custom" => StableDiffusionVersion::Custom,
+            _ => StableDiffusionVersion::V1_5,
+        };
+        sd_config.n_steps = args.sd_n_steps;
+        sd_config
+    }
 }
 
 pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<u8>>>> {
@@ -400,6 +457,43 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
         _ => vec![true],
     };
 
+    // with a local model directory check everything is there before loading anything
+    if config.model_dir.is_some() {
+        let mut required = Vec::new();
+        for first in which.iter() {
+            let (tokenizer_file, clip_file) = if *first {
+                (ModelFile::Tokenizer, ModelFile::Clip)
+            } else {
+                (ModelFile::Tokenizer2, ModelFile::Clip2)
+            };
+            required.push(tokenizer_file.get(
+                config.tokenizer.clone(),
+                config.sd_version,
+                config.use_f16,
+                &config.model_dir,
+            )?);
+            required.push(clip_file.get(
+                config.clip_weights.clone(),
+                config.sd_version,
+                false,
+                &config.model_dir,
+            )?);
+        }
+        required.push(ModelFile::Vae.get(
+            config.vae_weights.clone(),
+            config.sd_version,
+            config.use_f16,
+            &config.model_dir,
+        )?);
+        required.push(ModelFile::Unet.get(
+            config.unet_weights.clone(),
+            config.sd_version,
+            config.use_f16,
+            &config.model_dir,
+        )?);
+        require_files("stable diffusion", &required)?;
+    }
+
     let text_embeddings = which
         .iter()
         .map(|first| {
@@ -408,6 +502,7 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
                 &config.uncond_prompt,
                 config.tokenizer.clone(),
                 config.clip_weights.clone(),
+                &config.model_dir,
                 config.sd_version,
                 &sd_config,
                 config.use_f16,
@@ -423,7 +518,12 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
     debug!("Stable Diffusion: Text Embeddings - {text_embeddings:?}");
 
     debug!("Stable Diffusion: Building the autoencoder.");
-    let vae_weights = ModelFile::Vae.get(config.vae_weights, config.sd_version, config.use_f16)?;
+    let vae_weights = ModelFile::Vae.get(
+        config.vae_weights,
+        config.sd_version,
+        config.use_f16,
+        &config.model_dir,
+    )?;
     let vae = sd_config.build_vae(vae_weights, &device, dtype)?;
     let init_latent_dist = match &config.img2img {
         None => None,
@@ -433,8 +533,12 @@ pub async fn sd(config: SDConfig) -> Result<Vec<ImageBuffer<image::Rgb<u8>, Vec<
         }
     };
     debug!("Stable Diffusion: Building the unet.");
-    let unet_weights =
-        ModelFile::Unet.get(config.unet_weights, config.sd_version, config.use_f16)?;
+    let unet_weights = ModelFile::Unet.get(
+        config.unet_weights,
+        config.sd_version,
+        config.use_f16,
+        &config.model_dir,
+    )?;
     let unet = sd_config.build_unet(unet_weights, &device, 4, config.use_flash_attn, dtype)?;
 
     let t_start = if config.img2img.is_some() {
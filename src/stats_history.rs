@@ -0,0 +1,362 @@
+/*
+ * stats_history.rs
+ * ----------------
+ * Rolling history of system stats samples with min/max/average and a
+ * simple linear forecast per metric, so the LLM can tell a spike from a leak.
+*/
+
+use crate::current_unix_timestamp_ms;
+use crate::system_stats::SystemStats;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+
+// Minimum samples before a rate or forecast is reported
+const MIN_TREND_SAMPLES: usize = 3;
+// Only forecast capacity exhaustion inside this window
+const MAX_FORECAST_MINUTES: f64 = 7.0 * 24.0 * 60.0;
+
+#[derive(Serialize, Debug)]
+pub struct MetricTrend {
+    pub name: String,
+    pub unit: String,
+    pub current: f64,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub rate_per_minute: Option<f64>,
+    pub forecast: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatsTrends {
+    pub samples: usize,
+    pub window_seconds: u64,
+    pub metrics: Vec<MetricTrend>,
+    pub summary: Vec<String>,
+}
+
+struct Metric {
+    unit: &'static str,
+    capacity: Option<f64>,
+    points: VecDeque<(u64, f64)>,
+}
+
+pub struct StatsHistory {
+    max_samples: usize,
+    samples: usize,
+    /// Timestamps of the samples in the window, oldest first
+    sample_timestamps: VecDeque<u64>,
+    metrics: BTreeMap<String, Metric>,
+}
+
+impl StatsHistory {
+    pub fn new(max_samples: usize) -> Self {
+        StatsHistory {
+            max_samples,
+            samples: 0,
+            sample_timestamps: VecDeque::new(),
+            metrics: BTreeMap::new(),
+        }
+    }
+
+    fn record(
+        &mut self,
+        timestamp: u64,
+        name: String,
+        unit: &'static str,
+        value: f64,
+        capacity: Option<f64>,
+    ) {
+        let max_samples = self.max_samples;
+        let metric = self.metrics.entry(name).or_insert_with(|| Metric {
+            unit,
+            capacity,
+            points: VecDeque::new(),
+        });
+        metric.capacity = capacity;
+        metric.points.push_back((timestamp, value));
+        while metric.points.len() > max_samples {
+            metric.points.pop_front();
+        }
+    }
+
+    /// Add a stats sample to the history, dropping the oldest beyond the window.
+    pub fn push(&mut self, stats: &SystemStats) {
+        let ts = current_unix_timestamp_ms().unwrap_or(0);
+        const KB_PER_MB: f64 = 1024.0;
+        const BYTES_PER_MB: f64 = 1024.0 * 1024.0;
+
+        self.record(
+            ts,
+            "memory used".to_string(),
+            "MB",
+            stats.used_memory as f64 / KB_PER_MB,
+            Some(stats.total_memory as f64 / KB_PER_MB),
+        );
+        if stats.total_swap > 0 {
+            self.record(
+                ts,
+                "swap used".to_string(),
+                "MB",
+                stats.used_swap as f64 / KB_PER_MB,
+                Some(stats.total_swap as f64 / KB_PER_MB),
+            );
+        }
+        self.record(
+            ts,
+            "cpu usage".to_string(),
+            "%",
+            stats.cpu_usage as f64,
+            None,
+        );
+        self.record(
+            ts,
+            "load average 1m".to_string(),
+            "",
+            stats.load_avg.one,
+            None,
+        );
+
+        for disk in stats.disk_stats.iter() {
+            let used = stats_disk_used(disk.total_space, disk.available_space);
+            self.record(
+                ts,
+                format!("disk {} used", disk.mount_point),
+                "MB",
+                used as f64 / BYTES_PER_MB,
+                Some(disk.total_space as f64 / BYTES_PER_MB),
+            );
+        }
+
+        for process in stats.process_stats.iter() {
+            self.record(
+                ts,
+                format!("process {}({}) memory", process.name, process.pid),
+                "MB",
+                process.memory as f64 / KB_PER_MB,
+                None,
+            );
+            self.record(
+                ts,
+                format!("process {}({}) cpu", process.name, process.pid),
+                "%",
+                process.cpu_usage as f64,
+                None,
+            );
+        }
+
+        if let Some(max_temp) = stats
+            .temperatures
+            .iter()
+            .map(|c| c.temperature as f64)
+            .fold(None, |acc: Option<f64>, t| {
+                Some(acc.map_or(t, |a| a.max(t)))
+            })
+        {
+            self.record(ts, "max temperature".to_string(), "C", max_temp, None);
+        }
+
+        self.sample_timestamps.push_back(ts);
+        while self.sample_timestamps.len() > self.max_samples {
+            self.sample_timestamps.pop_front();
+        }
+        self.samples = self.sample_timestamps.len();
+
+        // forget metrics that stopped reporting (exited processes, unmounted disks)
+        let oldest = *self.sample_timestamps.front().unwrap_or(&ts);
+        self.metrics
+            .retain(|_, m| m.points.back().map_or(false, |(t, _)| *t >= oldest));
+    }
+
+    /// Compute min/max/avg, rate of change and capacity forecasts over the history window.
+    pub fn trends(&self) -> StatsTrends {
+        let window_seconds = match (
+            self.sample_timestamps.front(),
+            self.sample_timestamps.back(),
+        ) {
+            (Some(first), Some(last)) => last.saturating_sub(*first) / 1000,
+            _ => 0,
+        };
+
+        let mut metrics = Vec::new();
+        let mut summary = Vec::new();
+        for (name, metric) in self.metrics.iter() {
+            let values: Vec<f64> = metric.points.iter().map(|(_, v)| *v).collect();
+            if values.is_empty() {
+                continue;
+            }
+            let current = *values.last().unwrap();
+            let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let avg = values.iter().sum::<f64>() / values.len() as f64;
+
+            let rate_per_minute = if metric.points.len() >= MIN_TREND_SAMPLES {
+                linear_slope_per_minute(&metric.points)
+            } else {
+                None
+            };
+
+            let mut forecast = None;
+            if let (Some(rate), Some(capacity)) = (rate_per_minute, metric.capacity) {
+                if rate > 0.0 && current < capacity {
+                    let minutes = (capacity - current) / rate;
+                    if minutes <= MAX_FORECAST_MINUTES {
+                        forecast = Some(format!("full in ~{}", format_minutes(minutes)));
+                    }
+                }
+            }
+
+            if let Some(rate) = rate_per_minute {
+                // only call out metrics that are moving by more than 1% of their range per minute
+                let scale = metric.capacity.unwrap_or(max.abs().max(1.0));
+                if rate.abs() >= scale * 0.01 || forecast.is_some() {
+                    let direction = if rate > 0.0 { "growing" } else { "shrinking" };
+                    let mut line = format!(
+                        "{} {} {:.1}{}/min (now {:.1}{}, min {:.1}, max {:.1}, avg {:.1})",
+                        name,
+                        direction,
+                        rate.abs(),
+                        metric.unit,
+                        current,
+                        metric.unit,
+                        min,
+                        max,
+                        avg
+                    );
+                    if let Some(forecast) = &forecast {
+                        line.push_str(&format!(", {}", forecast));
+                    }
+                    summary.push(line);
+                } else if max - min > scale * 0.2 && (current - avg).abs() > (max - min) / 2.0 {
+                    summary.push(format!(
+                        "{} spike to {:.1}{} (avg {:.1}{}, no sustained trend)",
+                        name, current, metric.unit, avg, metric.unit
+                    ));
+                }
+            }
+
+            metrics.push(MetricTrend {
+                name: name.clone(),
+                unit: metric.unit.to_string(),
+                current,
+                min,
+                max,
+                avg,
+                rate_per_minute,
+                forecast,
+            });
+        }
+
+        StatsTrends {
+            samples: self.samples,
+            window_seconds,
+            metrics,
+            summary,
+        }
+    }
+}
+
+fn stats_disk_used(total_space: u64, available_space: u64) -> u64 {
+    total_space.saturating_sub(available_space)
+}
+
+// Least squares slope of value over time, in units per minute
+fn linear_slope_per_minute(points: &VecDeque<(u64, f64)>) -> Option<f64> {
+    let t0 = points.front()?.0;
+    let n = points.len() as f64;
+    let xs: Vec<f64> = points
+        .iter()
+        .map(|(t, _)| t.saturating_sub(t0) as f64 / 60_000.0)
+        .collect();
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, v)| *v).sum::<f64>() / n;
+    let mut num = 0.0;
+    let mut den = 0.0;
+    for (x, (_, y)) in xs.iter().zip(points.iter()) {
+        num += (x - mean_x) * (y - mean_y);
+        den += (x - mean_x) * (x - mean_x);
+    }
+    if den == 0.0 {
+        None
+    } else {
+        Some(num / den)
+    }
+}
+
+fn format_minutes(minutes: f64) -> String {
+    if minutes < 60.0 {
+        format!("{:.0}m", minutes.max(1.0))
+    } else if minutes < 48.0 * 60.0 {
+        format!("{:.0}h", minutes / 60.0)
+    } else {
+        format!("{:.0}d", minutes / (24.0 * 60.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(points: &[(u64, f64)], capacity: Option<f64>) -> StatsHistory {
+        let mut history = StatsHistory::new(10);
+        for (timestamp, value) in points {
+            history.record(
+                *timestamp,
+                "memory used".to_string(),
+                "MB",
+                *value,
+                capacity,
+            );
+        }
+        history
+    }
+
+    #[test]
+    fn growing_series_forecasts_when_full() {
+        let trends = history(&[(0, 10.0), (60_000, 20.0), (120_000, 30.0)], Some(100.0)).trends();
+        let metric = &trends.metrics[0];
+        assert_eq!(metric.rate_per_minute, Some(10.0));
+        assert_eq!((metric.min, metric.max, metric.avg), (10.0, 30.0, 20.0));
+        // 70MB left at 10MB a minute
+        assert_eq!(metric.forecast.as_deref(), Some("full in ~7m"));
+        assert_eq!(trends.summary.len(), 1);
+        assert!(trends.summary[0].starts_with("memory used growing 10.0MB/min"));
+    }
+
+    #[test]
+    fn flat_series_has_no_trend() {
+        let trends = history(&[(0, 50.0), (60_000, 50.0), (120_000, 50.0)], Some(100.0)).trends();
+        let metric = &trends.metrics[0];
+        assert_eq!(metric.rate_per_minute, Some(0.0));
+        assert!(metric.forecast.is_none());
+        assert!(trends.summary.is_empty());
+    }
+
+    #[test]
+    fn single_sample_has_no_rate() {
+        let trends = history(&[(0, 42.0)], Some(100.0)).trends();
+        let metric = &trends.metrics[0];
+        assert_eq!(
+            (metric.current, metric.min, metric.max, metric.avg),
+            (42.0, 42.0, 42.0, 42.0)
+        );
+        assert!(metric.rate_per_minute.is_none());
+        assert!(metric.forecast.is_none());
+    }
+
+    #[test]
+    fn timestamp_before_the_first_does_not_underflow() {
+        // a clock step back puts the second sample before the first, it counts as time 0
+        let points: VecDeque<(u64, f64)> = [(60_000, 1.0), (0, 2.0), (120_000, 3.0)]
+            .into_iter()
+            .collect();
+        let slope = linear_slope_per_minute(&points).unwrap();
+        assert!((slope - 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn samples_at_the_same_time_have_no_slope() {
+        let points: VecDeque<(u64, f64)> = [(1_000, 1.0), (1_000, 5.0)].into_iter().collect();
+        assert_eq!(linear_slope_per_minute(&points), None);
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * stop_sequences.rs
+ * -----------------
+ * Stop strings checked against the decoded token stream, text that may be
+ * the start of a stop string is held back until the next token decides it.
+*/
+
+/// Result of adding decoded text to the matcher.
+pub enum StopCheck {
+    /// Text safe to send, generation goes on.
+    Continue(String),
+    /// Text before the stop string, generation should end.
+    Stop(String),
+}
+
+pub struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+}
+
+impl StopMatcher {
+    pub fn new(stops: &[String]) -> Self {
+        StopMatcher {
+            stops: stops.iter().filter(|s| !s.is_empty()).cloned().collect(),
+            pending: String::new(),
+        }
+    }
+
+    /// Add the next decoded text, a stop string may span several tokens.
+    pub fn push(&mut self, text: &str) -> StopCheck {
+        self.pending.push_str(text);
+
+        // earliest stop string in the pending text wins
+        let found = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(index) = found {
+            let before = self.pending[..index].to_string();
+            self.pending.clear();
+            return StopCheck::Stop(before);
+        }
+
+        let hold = self.partial_match_len();
+        let emit_len = self.pending.len() - hold;
+        let emit = self.pending[..emit_len].to_string();
+        self.pending.drain(..emit_len);
+        StopCheck::Continue(emit)
+    }
+
+    /// Text held back when generation ended without a stop string.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    // Longest end of the pending text that is the start of a stop string
+    fn partial_match_len(&self) -> usize {
+        let mut hold = 0;
+        for stop in &self.stops {
+            for (index, _) in stop.char_indices().skip(1) {
+                let prefix = &stop[..index];
+                if index > hold && self.pending.ends_with(prefix) {
+                    hold = index;
+                }
+            }
+        }
+        hold
+    }
+}
+
+/// Parse the comma separated --stop option, `\n` is a newline.
+pub fn parse_stop_sequences(stop: &str) -> Vec<String> {
+    stop.split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.replace("\\n", "\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(stops: &[&str]) -> StopMatcher {
+        let stops: Vec<String> = stops.iter().map(|s| s.to_string()).collect();
+        StopMatcher::new(&stops)
+    }
+
+    // (stopped, text) of a push
+    fn push(matcher: &mut StopMatcher, text: &str) -> (bool, String) {
+        match matcher.push(text) {
+            StopCheck::Continue(text) => (false, text),
+            StopCheck::Stop(text) => (true, text),
+        }
+    }
+
+    #[test]
+    fn stop_string_split_across_tokens() {
+        let mut stops = matcher(&["<|eot_id|>"]);
+        assert_eq!(
+            push(&mut stops, "Hello <|eo"),
+            (false, "Hello ".to_string())
+        );
+        assert_eq!(push(&mut stops, "t_id"), (false, String::new()));
+        assert_eq!(push(&mut stops, "|> more"), (true, String::new()));
+    }
+
+    #[test]
+    fn partial_match_that_diverges_is_sent() {
+        let mut stops = matcher(&["</s>"]);
+        assert_eq!(push(&mut stops, "a </"), (false, "a ".to_string()));
+        assert_eq!(push(&mut stops, "b> c"), (false, "</b> c".to_string()));
+        assert_eq!(stops.flush(), "");
+    }
+
+    #[test]
+    fn earliest_of_several_stop_strings_wins() {
+        let mut stops = matcher(&["</s>", "USER:"]);
+        assert_eq!(
+            push(&mut stops, "The end. USER: hi</s>"),
+            (true, "The end. ".to_string())
+        );
+
+        let mut stops = matcher(&["</s>", "USER:"]);
+        assert_eq!(push(&mut stops, "Next US"), (false, "Next ".to_string()));
+        assert_eq!(push(&mut stops, "ER: hi"), (true, String::new()));
+    }
+
+    #[test]
+    fn held_text_is_flushed_at_the_end() {
+        let mut stops = matcher(&["STOP"]);
+        assert_eq!(push(&mut stops, "go ST"), (false, "go ".to_string()));
+        assert_eq!(stops.flush(), "ST");
+    }
+
+    #[test]
+    fn parse_stop_option() {
+        assert_eq!(parse_stop_sequences("a,\\n\\n,,b"), ["a", "\n\n", "b"]);
+    }
+}
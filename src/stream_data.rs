@@ -257,10 +257,12 @@ impl StreamData {
     pub fn increment_count(&mut self, count: u32) {
         self.count += count;
     }
-    pub fn set_continuity_counter(&mut self, continuity_counter: u8) {
+    // returns true if a continuity counter error was detected
+    pub fn set_continuity_counter(&mut self, continuity_counter: u8) -> bool {
         // check for continuity continuous increment and wrap around from 0 to 15
         let previous_continuity_counter = self.continuity_counter;
         self.continuity_counter = continuity_counter & 0x0F;
+        let mut cc_error = false;
         // check if we incremented without loss
         if self.continuity_counter != previous_continuity_counter + 1
             && self.continuity_counter != previous_continuity_counter
@@ -270,7 +272,7 @@ impl StreamData {
                 // check if previous value was 15
                 if previous_continuity_counter == 15 {
                     // no loss
-                    return;
+                    return false;
                 }
             }
             // loss
@@ -279,8 +281,10 @@ impl StreamData {
                 "Continuity Counter Error: PID: {} Previous: {} Current: {}",
                 self.pid, previous_continuity_counter, self.continuity_counter
             );
+            cc_error = true;
         }
         self.continuity_counter = continuity_counter;
+        cc_error
     }
     pub fn update_stats(&mut self, packet_size: usize, arrival_time: u64) {
         let bits = packet_size as u64 * 8; // Convert bytes to bits
@@ -404,6 +408,29 @@ impl Tr101290Errors {
             cat_errors: 0,
         }
     }
+
+    // Sum of all the error counters, used to detect when any of them jump.
+    // Widened to u64 so the sum of the u32 counters can't overflow.
+    pub fn total(&self) -> u64 {
+        [
+            self.ts_sync_byte_errors,
+            self.sync_byte_errors,
+            self.continuity_counter_errors,
+            self.pat_errors,
+            self.pmt_errors,
+            self.pid_map_errors,
+            self.transport_error_indicator_errors,
+            self.crc_errors,
+            self.pcr_repetition_errors,
+            self.pcr_discontinuity_indicator_errors,
+            self.pcr_accuracy_errors,
+            self.pts_errors,
+            self.cat_errors,
+        ]
+        .iter()
+        .map(|&count| count as u64)
+        .sum()
+    }
 }
 
 // TR 101 290 Priority 1 Check
@@ -577,8 +604,11 @@ pub fn process_packet(
             Arc::make_mut(&mut stream_data).update_stats(packet.len(), arrival_time);
             Arc::make_mut(&mut stream_data).increment_count(1);
             if stream_data.pid != 0x1FFF && is_mpegts {
-                Arc::make_mut(&mut stream_data)
-                    .set_continuity_counter(stream_data_packet.continuity_counter);
+                if Arc::make_mut(&mut stream_data)
+                    .set_continuity_counter(stream_data_packet.continuity_counter)
+                {
+                    errors.continuity_counter_errors += 1;
+                }
             }
             let uptime = arrival_time - stream_data.start_time;
 
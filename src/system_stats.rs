@@ -2,7 +2,7 @@ use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
-use sysinfo::{NetworkExt, NetworksExt};
+use sysinfo::{ComponentExt, DiskExt, NetworkExt, NetworksExt, PidExt, ProcessExt};
 use sysinfo::{ProcessorExt, System, SystemExt};
 
 static SYSTEM: Lazy<Mutex<(System, Instant)>> = Lazy::new(|| {
@@ -11,35 +11,108 @@ static SYSTEM: Lazy<Mutex<(System, Instant)>> = Lazy::new(|| {
     Mutex::new((system, Instant::now()))
 });
 
+// Companion service process names to report on alongside the bot itself
+static WATCHED_PROCESSES: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Set the process names (or script names in their command line) to report per-process stats for.
+pub fn set_watched_processes(names: Vec<String>) {
+    let mut watched = WATCHED_PROCESSES.lock().unwrap();
+    *watched = names
+        .into_iter()
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .collect();
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SystemStats {
-    total_memory: u64,
-    used_memory: u64,
-    total_swap: u64,
-    used_swap: u64,
-    cpu_usage: f32,
-    cpu_count: usize,
-    core_count: usize,
-    boot_time: u64,
-    load_avg: LoadAverage,
-    host_name: String,
-    kernel_version: String,
-    os_version: String,
-    network_stats: Vec<NetworkStats>,
+    pub total_memory: u64,
+    pub used_memory: u64,
+    pub total_swap: u64,
+    pub used_swap: u64,
+    pub cpu_usage: f32,
+    pub cpu_count: usize,
+    pub core_count: usize,
+    pub boot_time: u64,
+    pub load_avg: LoadAverage,
+    pub host_name: String,
+    pub kernel_version: String,
+    pub os_version: String,
+    pub network_stats: Vec<NetworkStats>,
+    pub core_stats: Vec<CoreStats>,
+    pub process_stats: Vec<ProcessStats>,
+    pub disk_stats: Vec<DiskStats>,
+    pub disk_io_stats: Vec<DiskIoStats>,
+    pub temperatures: Vec<ComponentStats>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NetworkStats {
-    name: String,
-    received: u64,
-    transmitted: u64,
+    pub name: String,
+    pub received: u64,
+    pub transmitted: u64,
+    pub packets_received: u64,
+    pub packets_transmitted: u64,
+    pub errors_on_received: u64,
+    pub errors_on_transmitted: u64,
+    pub total_errors_on_received: u64,
+    pub total_errors_on_transmitted: u64,
+    // drop counters are only available from /proc/net/dev on linux
+    pub total_dropped_received: Option<u64>,
+    pub total_dropped_transmitted: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CoreStats {
+    pub name: String,
+    pub cpu_usage: f32,
+    pub frequency: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProcessStats {
+    pub name: String,
+    pub pid: u32,
+    pub cpu_usage: f32,
+    pub memory: u64,
+    pub virtual_memory: u64,
+    pub run_time: u64,
+    pub disk_read_bytes: u64,
+    pub disk_written_bytes: u64,
+    pub total_disk_read_bytes: u64,
+    pub total_disk_written_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DiskStats {
+    pub name: String,
+    pub mount_point: String,
+    pub file_system: String,
+    pub total_space: u64,
+    pub available_space: u64,
+    pub used_percent: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DiskIoStats {
+    pub name: String,
+    pub total_read_bytes: u64,
+    pub total_written_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ComponentStats {
+    pub label: String,
+    pub temperature: f32,
+    pub max: f32,
+    pub critical: Option<f32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LoadAverage {
-    one: f64,
-    five: f64,
-    fifteen: f64,
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
 }
 
 pub fn get_system_stats() -> SystemStats {
@@ -68,17 +141,109 @@ pub fn get_system_stats() -> SystemStats {
     let boot_time = system.boot_time();
     let core_count = system.physical_core_count().unwrap_or_else(|| 0);
     let networks = system.networks();
+    let network_drops = read_network_drops();
     let network_stats = networks
         .iter()
-        .map(|(&ref name, data)| NetworkStats {
-            name: name.to_string(),
-            received: data.received(),
-            transmitted: data.transmitted(),
+        .map(|(&ref name, data)| {
+            let drops = network_drops.iter().find(|(n, _, _)| n == name);
+            NetworkStats {
+                name: name.to_string(),
+                received: data.received(),
+                transmitted: data.transmitted(),
+                packets_received: data.packets_received(),
+                packets_transmitted: data.packets_transmitted(),
+                errors_on_received: data.errors_on_received(),
+                errors_on_transmitted: data.errors_on_transmitted(),
+                total_errors_on_received: data.total_errors_on_received(),
+                total_errors_on_transmitted: data.total_errors_on_transmitted(),
+                total_dropped_received: drops.map(|(_, rx, _)| *rx),
+                total_dropped_transmitted: drops.map(|(_, _, tx)| *tx),
+            }
         })
         .collect();
 
     let cpu_usage = system.global_processor_info().cpu_usage();
 
+    let core_stats = system
+        .processors()
+        .iter()
+        .map(|p| CoreStats {
+            name: p.name().to_string(),
+            cpu_usage: p.cpu_usage(),
+            frequency: p.frequency(),
+        })
+        .collect();
+
+    // the bot itself plus any of the watched companion services
+    let own_pid = std::process::id();
+    let watched = WATCHED_PROCESSES.lock().unwrap().clone();
+    let mut process_stats: Vec<ProcessStats> = system
+        .processes()
+        .iter()
+        .filter(|(pid, process)| {
+            pid.as_u32() == own_pid
+                || watched.iter().any(|w| {
+                    process.name() == w.as_str()
+                        || process.cmd().iter().take(2).any(|arg| {
+                            std::path::Path::new(arg)
+                                .file_name()
+                                .map_or(false, |f| f.to_string_lossy() == w.as_str())
+                        })
+                })
+        })
+        .map(|(pid, process)| {
+            let disk_usage = process.disk_usage();
+            ProcessStats {
+                name: process.name().to_string(),
+                pid: pid.as_u32(),
+                cpu_usage: process.cpu_usage(),
+                memory: process.memory(),
+                virtual_memory: process.virtual_memory(),
+                run_time: process.run_time(),
+                disk_read_bytes: disk_usage.read_bytes,
+                disk_written_bytes: disk_usage.written_bytes,
+                total_disk_read_bytes: disk_usage.total_read_bytes,
+                total_disk_written_bytes: disk_usage.total_written_bytes,
+            }
+        })
+        .collect();
+    process_stats.sort_by_key(|p| p.pid);
+
+    let disk_stats = system
+        .disks()
+        .iter()
+        .map(|disk| {
+            let total_space = disk.total_space();
+            let available_space = disk.available_space();
+            let used_percent = if total_space > 0 {
+                (total_space - available_space) as f32 / total_space as f32 * 100.0
+            } else {
+                0.0
+            };
+            DiskStats {
+                name: disk.name().to_string_lossy().to_string(),
+                mount_point: disk.mount_point().to_string_lossy().to_string(),
+                file_system: String::from_utf8_lossy(disk.file_system()).to_string(),
+                total_space,
+                available_space,
+                used_percent,
+            }
+        })
+        .collect();
+
+    let disk_io_stats = read_disk_io();
+
+    let temperatures = system
+        .components()
+        .iter()
+        .map(|c| ComponentStats {
+            label: c.label().to_string(),
+            temperature: c.temperature(),
+            max: c.max(),
+            critical: c.critical(),
+        })
+        .collect();
+
     SystemStats {
         total_memory: system.total_memory(),
         used_memory: system.used_memory(),
@@ -93,5 +258,79 @@ pub fn get_system_stats() -> SystemStats {
         kernel_version,
         os_version,
         network_stats,
+        core_stats,
+        process_stats,
+        disk_stats,
+        disk_io_stats,
+        temperatures,
     }
 }
+
+// Per interface (name, rx dropped, tx dropped) from /proc/net/dev
+#[cfg(target_os = "linux")]
+fn read_network_drops() -> Vec<(String, u64, u64)> {
+    let contents = match std::fs::read_to_string("/proc/net/dev") {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(),
+    };
+    contents
+        .lines()
+        .skip(2) // header lines
+        .filter_map(|line| {
+            let (name, counters) = line.split_once(':')?;
+            let fields: Vec<u64> = counters
+                .split_whitespace()
+                .filter_map(|f| f.parse().ok())
+                .collect();
+            // rx: bytes packets errs drop ... (8 fields), tx: bytes packets errs drop ...
+            if fields.len() < 12 {
+                return None;
+            }
+            Some((name.trim().to_string(), fields[3], fields[11]))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_network_drops() -> Vec<(String, u64, u64)> {
+    Vec::new()
+}
+
+// Whole disk read/write totals from /proc/diskstats, partitions and virtual devices skipped
+#[cfg(target_os = "linux")]
+fn read_disk_io() -> Vec<DiskIoStats> {
+    const SECTOR_SIZE: u64 = 512;
+    let contents = match std::fs::read_to_string("/proc/diskstats") {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(),
+    };
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                return None;
+            }
+            let name = fields[2];
+            if name.starts_with("loop") || name.starts_with("ram") {
+                return None;
+            }
+            // only report whole devices that have a /sys/block entry
+            if !std::path::Path::new(&format!("/sys/block/{}", name)).exists() {
+                return None;
+            }
+            let sectors_read: u64 = fields[5].parse().ok()?;
+            let sectors_written: u64 = fields[9].parse().ok()?;
+            Some(DiskIoStats {
+                name: name.to_string(),
+                total_read_bytes: sectors_read * SECTOR_SIZE,
+                total_written_bytes: sectors_written * SECTOR_SIZE,
+            })
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_disk_io() -> Vec<DiskIoStats> {
+    Vec::new()
+}
@@ -0,0 +1,137 @@
+/*
+ * token_budget.rs
+ * ---------------
+ * Token counting with the model's own tokenizer and a context window budget,
+ * history is trimmed by dropping whole oldest messages first.
+*/
+
+use crate::count_tokens;
+use crate::openai_api::Message;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+// Role and turn marker tokens the chat template adds around each message
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// Counts tokens with the model tokenizer, or the word chunk estimate without one.
+#[derive(Clone, Default)]
+pub struct TokenCounter {
+    tokenizer: Option<Arc<Tokenizer>>,
+}
+
+impl TokenCounter {
+    pub fn new(tokenizer: Option<Arc<Tokenizer>>) -> Self {
+        TokenCounter { tokenizer }
+    }
+
+    /// True when counting with the model tokenizer rather than the estimate.
+    pub fn is_exact(&self) -> bool {
+        self.tokenizer.is_some()
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        match &self.tokenizer {
+            Some(tokenizer) => match tokenizer.encode(text, false) {
+                Ok(encoding) => encoding.len(),
+                Err(_) => count_tokens(text),
+            },
+            None => count_tokens(text),
+        }
+    }
+
+    pub fn count_message(&self, message: &Message) -> usize {
+        self.count(&message.content) + MESSAGE_OVERHEAD_TOKENS
+    }
+
+    pub fn count_messages(&self, messages: &[Message]) -> usize {
+        messages.iter().map(|m| self.count_message(m)).sum()
+    }
+}
+
+/// Context window of the local candle models, 0 when unknown.
+pub fn context_window(candle_llm: &str) -> usize {
+    match candle_llm {
+        "mistral" => 32768,
+        "gemma" => 8192,
+        "llama" => 8192,
+        "phi" => 4096,
+        "qwen" => 32768,
+        _ => 0,
+    }
+}
+
+/// Drop the oldest non-system messages until the conversation fits in `budget` tokens.
+/// System messages and the newest message are always kept, returns how many were dropped.
+pub fn trim_messages(messages: &mut Vec<Message>, counter: &TokenCounter, budget: usize) -> usize {
+    let mut total = counter.count_messages(messages);
+    let mut dropped = 0;
+    let mut index = 0;
+    while total > budget && index + 1 < messages.len() {
+        if messages[index].role == "system" {
+            index += 1;
+            continue;
+        }
+        total -= counter.count_message(&messages[index]);
+        messages.remove(index);
+        dropped += 1;
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Two short words and the message overhead, 6 estimated tokens each
+    fn conversation() -> Vec<Message> {
+        vec![
+            message("system", "be nice"),
+            message("user", "hi you"),
+            message("assistant", "oh hey"),
+            message("user", "how now"),
+        ]
+    }
+
+    fn contents(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[test]
+    fn fits_without_dropping() {
+        let mut messages = conversation();
+        assert_eq!(
+            trim_messages(&mut messages, &TokenCounter::default(), 24),
+            0
+        );
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn drops_oldest_whole_messages_and_keeps_the_system_message() {
+        let mut messages = conversation();
+        let counter = TokenCounter::default();
+        assert_eq!(trim_messages(&mut messages, &counter, 18), 1);
+        assert_eq!(contents(&messages), ["be nice", "oh hey", "how now"]);
+
+        let mut messages = conversation();
+        assert_eq!(trim_messages(&mut messages, &counter, 15), 2);
+        // never cut in the middle, the kept messages are whole
+        assert_eq!(contents(&messages), ["be nice", "how now"]);
+        assert_eq!(counter.count_messages(&messages), 12);
+    }
+
+    #[test]
+    fn keeps_the_newest_message_over_budget() {
+        let mut messages = conversation();
+        assert_eq!(trim_messages(&mut messages, &TokenCounter::default(), 5), 2);
+        assert_eq!(contents(&messages), ["be nice", "how now"]);
+    }
+}
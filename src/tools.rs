@@ -0,0 +1,228 @@
+/*
+ * tools.rs
+ * --------
+ * Local tools the LLM can call through the OpenAI tools API, so it can fetch
+ * system stats, the MPEG-TS PID map or twitch chat history when it needs them.
+*/
+
+use crate::openai_api::ToolCall;
+use crate::stream_data::get_pid_map;
+use crate::system_stats::get_system_stats;
+use crate::twitch_client::{chat_history_message, TWITCH_CHAT_DB};
+use anyhow::{anyhow, Result};
+use log::info;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+// Most chat history messages a tool call returns
+const MAX_TWITCH_HISTORY: u64 = 100;
+
+/// Tool definition sent in the chat completions request.
+#[derive(Serialize, Clone, Debug)]
+pub struct ToolDefinition {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments
+    pub parameters: Value,
+}
+
+pub trait LocalTool: Send + Sync {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    fn parameters(&self) -> Value;
+    /// Run the tool, the result is sent back to the LLM as text.
+    fn call(&self, arguments: &Value) -> Result<String>;
+}
+
+struct SystemStatsTool;
+
+impl LocalTool for SystemStatsTool {
+    fn name(&self) -> &str {
+        "get_system_stats"
+    }
+
+    fn description(&self) -> &str {
+        "Get the current system stats, cpu, memory, disk, network and process usage."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({"type": "object", "properties": {}})
+    }
+
+    fn call(&self, _arguments: &Value) -> Result<String> {
+        Ok(serde_json::to_string(&get_system_stats())?)
+    }
+}
+
+struct PidMapTool;
+
+impl LocalTool for PidMapTool {
+    fn name(&self) -> &str {
+        "get_pid_map"
+    }
+
+    fn description(&self) -> &str {
+        "Get the MPEG-TS PID map of the captured stream, each PID with its stream type and counters."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({"type": "object", "properties": {}})
+    }
+
+    fn call(&self, _arguments: &Value) -> Result<String> {
+        let pid_map = get_pid_map();
+        if pid_map.is_empty() {
+            Ok("No PIDs captured yet.".to_string())
+        } else {
+            Ok(pid_map)
+        }
+    }
+}
+
+struct TwitchHistoryTool;
+
+impl LocalTool for TwitchHistoryTool {
+    fn name(&self) -> &str {
+        "get_twitch_chat_history"
+    }
+
+    fn description(&self) -> &str {
+        "Look up the recent twitch chat history with a user."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "user": {"type": "string", "description": "Twitch user name"},
+                "limit": {"type": "integer", "description": "Max recent messages to return, up to 100"}
+            },
+            "required": ["user"]
+        })
+    }
+
+    fn call(&self, arguments: &Value) -> Result<String> {
+        let user = arguments
+            .get("user")
+            .and_then(|u| u.as_str())
+            .ok_or_else(|| anyhow!("missing the user argument"))?;
+        let limit = arguments
+            .get("limit")
+            .and_then(|l| l.as_u64())
+            .unwrap_or(20)
+            .min(MAX_TWITCH_HISTORY);
+        let conn = Connection::open(TWITCH_CHAT_DB)?;
+        let mut history: Vec<String> = conn
+            .prepare("SELECT message FROM chat_history WHERE user_id = ? ORDER BY id DESC LIMIT ?")?
+            .query_map(params![user, limit], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(|row| {
+                let message = chat_history_message(row);
+                format!("{}: {}", message.role, message.content)
+            })
+            .collect();
+        if history.is_empty() {
+            Ok(format!("No chat history with {}.", user))
+        } else {
+            // oldest first so it reads as the conversation
+            history.reverse();
+            Ok(history.join("\n"))
+        }
+    }
+}
+
+/// Tools available to the LLM by name.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn LocalTool>>,
+}
+
+impl ToolRegistry {
+    /// Registry of the built-in tools, "all" or a comma separated list of tool names.
+    pub fn from_names(names: &str) -> Result<Self> {
+        let builtin: Vec<Arc<dyn LocalTool>> = vec![
+            Arc::new(SystemStatsTool),
+            Arc::new(PidMapTool),
+            Arc::new(TwitchHistoryTool),
+        ];
+        let mut registry = ToolRegistry::default();
+        if names.is_empty() {
+            return Ok(registry);
+        }
+        if names == "all" {
+            registry.tools = builtin;
+            return Ok(registry);
+        }
+        for name in names.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+            match builtin.iter().find(|tool| tool.name() == name) {
+                Some(tool) => registry.register(tool.clone()),
+                None => {
+                    return Err(anyhow!(
+                        "unknown tool {}, available tools are {}",
+                        name,
+                        builtin
+                            .iter()
+                            .map(|tool| tool.name())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                }
+            }
+        }
+        Ok(registry)
+    }
+
+    pub fn register(&mut self, tool: Arc<dyn LocalTool>) {
+        self.tools.push(tool);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools
+            .iter()
+            .map(|tool| ToolDefinition {
+                tool_type: "function".to_string(),
+                function: FunctionDefinition {
+                    name: tool.name().to_string(),
+                    description: tool.description().to_string(),
+                    parameters: tool.parameters(),
+                },
+            })
+            .collect()
+    }
+
+    /// Run a tool call, failures are returned as text so the LLM can see what went wrong.
+    pub fn call(&self, tool_call: &ToolCall) -> String {
+        let name = &tool_call.function.name;
+        let tool = match self.tools.iter().find(|tool| tool.name() == name) {
+            Some(tool) => tool,
+            None => return format!("Error: unknown tool {}", name),
+        };
+        let arguments: Value = if tool_call.function.arguments.trim().is_empty() {
+            json!({})
+        } else {
+            match serde_json::from_str(&tool_call.function.arguments) {
+                Ok(arguments) => arguments,
+                Err(e) => return format!("Error: invalid arguments for {}: {}", name, e),
+            }
+        };
+        info!("Calling tool {} with {}", name, arguments);
+        match tool.call(&arguments) {
+            Ok(result) => result,
+            Err(e) => format!("Error: {} failed: {}", name, e),
+        }
+    }
+}
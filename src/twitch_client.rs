@@ -1,7 +1,9 @@
 
 use crate::args::Args;
-use crate::candle_gemma::gemma;
-use crate::candle_mistral::mistral;
+use crate::llm_backend::{candle_backend, default_chat_format, GenerationParams};
+use crate::model_files::ModelFiles;
+use crate::openai_api::Message;
+use crate::usage::Usage;
 use anyhow::Result;
 use rand::Rng;
 use rusqlite::{params, Connection};
@@ -10,6 +12,19 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc::{self};
 
+/// Chat history database shared with the LLM tools.
+pub const TWITCH_CHAT_DB: &str = "db/twitch_chat.db";
+
+/// A chat_history row as a message, older rows are pre-formatted prompt strings
+/// rather than json messages and are taken as user messages.
+pub fn chat_history_message(row: String) -> Message {
+    serde_json::from_str::<Message>(&row).unwrap_or(Message {
+        role: "user".to_string(),
+        content: row,
+        ..Default::default()
+    })
+}
+
 pub async fn daemon(
     nick: String,
     token: String,
@@ -86,7 +101,7 @@ async fn on_msg(
         return Ok(());
     }
 
-    let db_path = "db/twitch_chat.db";
+    let db_path = TWITCH_CHAT_DB;
     let conn = Connection::open(db_path)?;
 
     conn.execute(
@@ -101,154 +116,85 @@ async fn on_msg(
     let user_id = msg.sender().name();
 
     // Retrieve the chat history for the specific user
-    let mut chat_messages: Vec<String> = conn
-        .prepare("SELECT message FROM chat_history WHERE user_id = ?")?
-        .query_map(params![user_id], |row| row.get(0))?
-        .collect::<Result<_, _>>()?;
+    let mut chat_messages: Vec<Message> = conn
+        .prepare("SELECT message FROM chat_history WHERE user_id = ? ORDER BY id")?
+        .query_map(params![user_id], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .map(chat_history_message)
+        .collect();
 
     // send message to the LLM and get an answer to send back to the user.
     // also send the message to the main LLM loop to keep history context of the conversation
     if !msg.text().starts_with("!help") && !msg.text().starts_with("!message") {
         // LLM Thread
         let (external_sender, mut external_receiver) = tokio::sync::mpsc::channel::<String>(100);
-        let max_tokens = args.twitch_max_tokens_chat;
-        let temperature = 0.8;
-        let quantized = false;
         let max_messages = args.twitch_chat_history;
-
-        let system_start_token = if args.twitch_model == "gemma" {
-            "<start_of_turn>"
-        } else {
-            "<<SYS>>"
-        };
-
-        let system_end_token = if args.twitch_model == "gemma" {
-            "<end_of_turn>"
-        } else {
-            "<</SYS>>"
-        };
-
-        let assistant_start_token = if args.twitch_model == "gemma" {
-            "<start_of_turn>"
-        } else {
-            ""
-        };
-
-        let assistant_end_token = if args.twitch_model == "gemma" {
-            "<end_of_turn>"
-        } else {
-            ""
-        };
-
-        let start_token = if args.twitch_model == "gemma" {
-            "<start_of_turn>"
-        } else {
-            "[INST]"
-        };
-
-        let end_token = if args.twitch_model == "gemma" {
-            "<end_of_turn>"
-        } else {
-            "[/INST]"
-        };
-
-        let bos_token = if args.twitch_model == "gemma" {
-            ""
-        } else {
-            "<s>"
+        let mut params = GenerationParams {
+            temperature: args.twitch_temperature,
+            ..GenerationParams::from_args(&args, args.twitch_max_tokens_chat)
         };
+        // some models separate a chat reply from rambling with <|im_sep|>
+        params.stop.push("<|im_sep|>".to_string());
 
-        let eos_token = if args.twitch_model == "gemma" {
-            ""
+        let model_id = if args.twitch_model == "gemma" {
+            "2b-it"
         } else {
-            "</s>"
+            "auto"
         };
 
-        let user_name = if args.twitch_model == "gemma" {
-            "user"
-        } else {
-            ""
-        };
+        // Keep the latest max messages, a question and answer each
+        if chat_messages.len() > max_messages * 2 {
+            let excess = chat_messages.len() - max_messages * 2;
+            chat_messages.drain(..excess);
+        }
 
-        let assistant_name = if args.twitch_model == "gemma" {
-            "model"
-        } else {
-            ""
+        let user_message = Message {
+            role: "user".to_string(),
+            content: format!(
+                "twitch chat user {} asked {}",
+                msg.sender().name(),
+                msg.text().to_string()
+            ),
+            ..Default::default()
         };
 
-        // Truncate the chat_messages array to 3 messages max messages
-        if chat_messages.len() > max_messages {
-            chat_messages.truncate(max_messages);
-        }
-
-        // build a string out of the chat_messages array of strings
-        // that have each message in the format <s><start_of_turn>user {message}<end_of_turn></s>
-        let mut chat_messages_history = String::new();
-        for message in chat_messages.iter() {
-            chat_messages_history.push_str(&format!("{}", message));
-        }
+        let mut messages = vec![Message {
+            role: "system".to_string(),
+            content: args.twitch_prompt.clone(),
+            ..Default::default()
+        }];
+        messages.extend(chat_messages.iter().cloned());
+        messages.push(user_message.clone());
+        // open the assistant turn for the model to answer in
+        messages.push(Message {
+            role: "assistant".to_string(),
+            content: String::new(),
+            ..Default::default()
+        });
 
-        // Send message to the AI through mpsc channels format to model specs
-        let msg_text = format!(
-            "{}{}{} {}{}{}{}{}{}{}{} twitch chat user {} asked {}{}{}{} ",
-            bos_token,
-            system_start_token,
-            assistant_name,
-            args.twitch_prompt.clone(),
-            system_end_token,
-            eos_token,
-            bos_token,
-            chat_messages_history,
-            bos_token,
-            start_token,
-            user_name,
-            msg.sender().name(),
-            msg.text().to_string(),
-            end_token,
-            assistant_start_token,
-            assistant_name,
-        ); // Clone the message text
-
-        println!("\nTwitch sending msg_text:\n{}\n", msg_text);
-
-        let llm_thread = if args.twitch_model == "gemma" {
-            tokio::spawn(async move {
-                if let Err(e) = gemma(
-                    msg_text,
-                    max_tokens,
-                    temperature,
-                    quantized,
-                    Some("2b-it".to_string()),
-                    external_sender,
-                ) {
-                    eprintln!("Error running twitch gemma: {}", e);
-                }
-            })
-        } else if args.twitch_model == "mistral" {
-            tokio::spawn(async move {
-                if let Err(e) = mistral(
-                    msg_text,
-                    max_tokens,
-                    temperature,
-                    quantized,
-                    Some("auto".to_string()),
-                    external_sender,
-                ) {
-                    eprintln!("Error running twitch mistral: {}", e);
-                }
-            })
-        } else {
-            // print message and error out
-            eprintln!(
-                "Error: Invalid model specified for twitch chat {}",
-                args.twitch_model
-            );
-            tokio::spawn(async move {
-                external_sender
-                    .send("Error: Invalid model specified for twitch chat".to_string())
-                    .await
-                    .unwrap();
-            })
+        let llm_thread = match candle_backend(
+            &args.twitch_model,
+            model_id,
+            false,
+            ModelFiles::new(&args.twitch_model_dir, "", "", ""),
+            default_chat_format(&args.twitch_model),
+            args.model_queue_size,
+        ) {
+            Ok(backend) => backend.generate(messages, params, external_sender),
+            Err(e) => {
+                // print message and error out
+                eprintln!(
+                    "Error: Invalid model specified for twitch chat {}: {}",
+                    args.twitch_model, e
+                );
+                tokio::spawn(async move {
+                    external_sender
+                        .send("Error: Invalid model specified for twitch chat".to_string())
+                        .await?;
+                    Ok::<Usage, anyhow::Error>(Usage::default())
+                })
+            }
         };
 
         // thread token collection and wait for it to finish
@@ -261,7 +207,9 @@ async fn on_msg(
         });
 
         // wait for llm thread to finish
-        llm_thread.await?;
+        if let Err(e) = llm_thread.await? {
+            eprintln!("Error running twitch {}: {}", args.twitch_model, e);
+        }
 
         let answer = token_thread.await?;
 
@@ -270,15 +218,8 @@ async fn on_msg(
 
         println!("\nTwitch received answer:\n{}\n", answer);
 
-        // Check if the answer contains <|im_sep|>
-        let truncated_answer = if let Some(sep_index) = answer.find("<|im_sep|>") {
-            &answer[..sep_index]
-        } else {
-            &answer
-        };
-
         // Split the answer into sections based on newline characters
-        let sections: Vec<&str> = truncated_answer.split('\n').collect();
+        let sections: Vec<&str> = answer.split('\n').collect();
 
         for section in sections {
             // Split the section into sentences
@@ -338,28 +279,18 @@ async fn on_msg(
             }
         }
 
-        // add message to the chat_messages history of strings
-        let full_message = format!(
-            "{}{}{} {} asked {}{}{}{} {}{}{}",
-            bos_token,
-            start_token,
-            user_name,
-            msg.sender().name(),
-            msg.text().to_string(),
-            end_token,
-            assistant_start_token,
-            assistant_name,
-            truncated_answer,
-            assistant_end_token,
-            eos_token
-        );
-        chat_messages.push(full_message.clone());
-
-        // Insert the new message into the database
-        conn.execute(
-            "INSERT INTO chat_history (user_id, message) VALUES (?, ?)",
-            params![user_id, full_message],
-        )?;
+        // Insert the question and answer into the database chat history
+        let assistant_message = Message {
+            role: "assistant".to_string(),
+            content: answer.clone(),
+            ..Default::default()
+        };
+        for message in [&user_message, &assistant_message] {
+            conn.execute(
+                "INSERT INTO chat_history (user_id, message) VALUES (?, ?)",
+                params![user_id, serde_json::to_string(message)?],
+            )?;
+        }
 
         // Send message to the main loop through mpsc channels
         tx.send(format!(
@@ -0,0 +1,161 @@
+/*
+ * usage.rs
+ * --------
+ * Usage and latency records for each LLM call, logged as JSON and summed
+ * per backend and model over a session to compare backends and API spend.
+*/
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Usage of a single generation.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Usage {
+    pub backend: String,
+    pub model: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Counts from the server or model tokenizer, false when estimated
+    pub exact: bool,
+    pub time_to_first_token_ms: u64,
+    pub total_ms: u64,
+    pub tokens_per_second: f64,
+}
+
+impl Usage {
+    /// Log the record as a single JSON line.
+    pub fn log(&self) {
+        match serde_json::to_string(self) {
+            Ok(json) => info!("LLM usage: {}", json),
+            Err(e) => info!("LLM usage could not be serialized: {}", e),
+        }
+    }
+}
+
+/// Times a generation from the request to the first token and the end.
+pub struct UsageTimer {
+    start: Instant,
+    first_token: Option<Duration>,
+}
+
+impl UsageTimer {
+    pub fn start() -> Self {
+        UsageTimer {
+            start: Instant::now(),
+            first_token: None,
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Record the first token now, later calls are ignored.
+    pub fn first_token(&mut self) {
+        self.first_token_at(self.start.elapsed());
+    }
+
+    /// Record the first token at `elapsed` since the start, later calls are ignored.
+    pub fn first_token_at(&mut self, elapsed: Duration) {
+        if self.first_token.is_none() {
+            self.first_token = Some(elapsed);
+        }
+    }
+
+    pub fn finish(
+        &self,
+        backend: &str,
+        model: &str,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        exact: bool,
+    ) -> Usage {
+        let total = self.start.elapsed();
+        // generation speed after the first token, the prompt processing time is in the first token
+        let generating = total.saturating_sub(self.first_token.unwrap_or_default());
+        let tokens_per_second = if generating.as_secs_f64() > 0.0 {
+            completion_tokens as f64 / generating.as_secs_f64()
+        } else {
+            0.0
+        };
+        Usage {
+            backend: backend.to_string(),
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens,
+            exact,
+            time_to_first_token_ms: self.first_token.unwrap_or(total).as_millis() as u64,
+            total_ms: total.as_millis() as u64,
+            tokens_per_second,
+        }
+    }
+}
+
+/// Usage summed over many calls of one backend and model.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct UsageTotals {
+    pub calls: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub time_to_first_token_ms: u64,
+    pub total_ms: u64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, usage: &Usage) {
+        self.calls += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.time_to_first_token_ms += usage.time_to_first_token_ms;
+        self.total_ms += usage.total_ms;
+    }
+
+    pub fn average_time_to_first_token_ms(&self) -> u64 {
+        if self.calls == 0 {
+            0
+        } else {
+            self.time_to_first_token_ms / self.calls as u64
+        }
+    }
+
+    pub fn tokens_per_second(&self) -> f64 {
+        let generating_ms = self.total_ms.saturating_sub(self.time_to_first_token_ms);
+        if generating_ms == 0 {
+            0.0
+        } else {
+            self.completion_tokens as f64 * 1000.0 / generating_ms as f64
+        }
+    }
+}
+
+/// Usage of a session keyed by "backend/model".
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SessionUsage {
+    pub by_backend: BTreeMap<String, UsageTotals>,
+}
+
+impl SessionUsage {
+    pub fn add(&mut self, usage: &Usage) {
+        self.by_backend
+            .entry(format!("{}/{}", usage.backend, usage.model))
+            .or_default()
+            .add(usage);
+    }
+
+    /// Log the totals of each backend.
+    pub fn log(&self) {
+        for (backend, totals) in &self.by_backend {
+            info!(
+                "Session usage {}: {} calls, {}/{} prompt/completion tokens, {}ms avg first token, {:.2}tps",
+                backend,
+                totals.calls,
+                totals.prompt_tokens,
+                totals.completion_tokens,
+                totals.average_time_to_first_token_ms(),
+                totals.tokens_per_second()
+            );
+        }
+    }
+}